@@ -16,7 +16,8 @@ def get_data() -> Dict[str, List[List[Datum]]]:
         path = join("data", file)
         type = "regional" if file[0] == 'r' else "cyclical"
         with open(path, "r", newline="") as csvfile:
-            reader = csv.DictReader(csvfile, ["time", "position"])
+            lines = (line for line in csvfile if not line.startswith("#"))
+            reader = csv.DictReader(lines, ["time", "position"])
             run = []
             for row in reader:
                 run.append({
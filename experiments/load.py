@@ -18,7 +18,8 @@ def get_data_single(dir) -> Dict[str, List[List[Datum]]]:
         path = join(data_dir, file)
         type = "regional" if file[0] == 'r' else "cyclical"
         with open(path, "r", newline="") as csvfile:
-            reader = csv.DictReader(csvfile, ["time", "position"])
+            lines = (line for line in csvfile if not line.startswith("#"))
+            reader = csv.DictReader(lines, ["time", "position"])
             run = []
             for row in reader:
                 run.append({
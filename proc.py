@@ -1,32 +1,25 @@
 from typing import List, Tuple
-import pexpect
-import re
+import csv
+import os
+import subprocess
+import tempfile
 
 BIN = "./target/release/blob"
-PATTERN = "b'Vector { x: ([-\\d.]+), y: ([-\\d.]+) }'"
 
-def get_points(output: bytes | str) -> List[Tuple[float, float]]:
+def get_points(path: str) -> List[Tuple[float, float]]:
     points = []
-    for line in output.splitlines():
-        m = re.match(PATTERN, str(line))
-        if not m: continue
-        g = m.groups()
-        points.append((float(g[0]), float(g[1])))
+    with open(path) as file:
+        for row in csv.DictReader(file):
+            points.append((float(row["x"]), float(row["y"])))
     return points
 
-def get_output(proc) -> bytes:
-    proc.sendline("")
-    proc.read_nonblocking(10)
-    return proc.read_nonblocking(10000)
-
-def get_proc() -> pexpect.spawn:
-    return pexpect.spawn(BIN)
+def run(run_length: float, *flags: str) -> List[Tuple[float, float]]:
+    with tempfile.TemporaryDirectory() as dir:
+        subprocess.check_call([
+            BIN, "--nogui", "--run-length", str(run_length),
+            "--output-dir", dir, "--channels", "com", *flags,
+        ])
+        return get_points(os.path.join(dir, "com.csv"))
 
 if __name__ == "__main__":
-    proc = get_proc()
-
-    for i in range(2):
-        output = get_output(proc)
-        print(get_points(output))
-
-    proc.close()
+    print(run(10.0))
@@ -0,0 +1,283 @@
+use std::{cmp::Ordering, f32::consts::TAU};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    body::{spawn_node, NodeSpec, DRAG_EDGE},
+    brain::{Inputs, UpdateFlux, CTRNN},
+    physics::*,
+    pressure::{signed_area, Pressure},
+    worm::{Control, Head, Neurons, Segment, SpringClasses, SpringHidden, WormController},
+};
+
+/// Spacing between neighbouring nodes, also the rest length the mappings
+/// drive control springs around.
+const SPACING: f32 = 0.5;
+
+/// Soft bodies other than the worm. Their control springs are numbered from 1
+/// like the worm's, so every mapping drives them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum BlobShape {
+    /// A ring of `nodes` around a center node, joined to it by driven spokes
+    /// and kept inflated by `pressure`, if given.
+    Radial {
+        nodes: usize,
+        #[serde(default)]
+        pressure: f32,
+    },
+    /// `width` columns of `height` nodes, braced across every cell. The rows
+    /// above and below the middle are driven, bending the sheet.
+    Grid { width: usize, height: usize },
+    /// `arms` chains of `length` driven springs reaching out from a center
+    /// node, their first nodes joined in a ring once there are three.
+    Star { arms: usize, length: usize },
+}
+
+impl BlobShape {
+    /// Rejects shapes too small to build, caught by `Config::check`.
+    pub fn check(&self) -> Result<(), String> {
+        match *self {
+            Self::Radial { nodes, .. } if nodes < 3 => Err("a radial blob needs at least 3 nodes".to_string()),
+            Self::Grid { width, height } if width == 0 || height == 0 => {
+                Err("a grid blob needs at least one column and row".to_string())
+            }
+            Self::Star { arms, length } if arms < 2 || length == 0 => {
+                Err("a star blob needs at least 2 arms of at least one node".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Marks a body built by `blob_builder`, which does not follow the body plan
+/// and so never grows.
+#[derive(Component)]
+pub struct Blob;
+
+fn node(parent: &mut ChildBuilder, position: Vec3) -> Entity {
+    spawn_node(parent, &NodeSpec::default(), position).id()
+}
+
+fn control(
+    parent: &mut ChildBuilder,
+    springs: &SpringClasses,
+    a: Entity,
+    b: Entity,
+    index: usize,
+    side: f32
+) {
+    parent.spawn((
+        springs.control.spring(a, b, SPACING),
+        Control { index: index as i32, side },
+        Drag(DRAG_EDGE)
+    ));
+}
+
+/// Alternates sides so neighbouring control springs move in antiphase.
+fn alternate(i: usize) -> f32 {
+    if i % 2 == 0 { -1.0 } else { 1.0 }
+}
+
+fn radial(
+    parent: &mut ChildBuilder,
+    springs: &SpringClasses,
+    position: Vec3,
+    count: usize,
+    pressure: f32
+) -> Vec<Segment<Entity>> {
+    let center = spawn_node(parent, &NodeSpec::default(), position).insert(Head).id();
+    let points: Vec<Vec3> = (0..count)
+        .map(|i| {
+            let angle = i as f32 / count as f32 * TAU;
+            position + Vec3::new(angle.cos(), angle.sin(), 0.0) * SPACING
+        })
+        .collect();
+    let rim: Vec<Entity> = points.iter().map(|point| node(parent, *point)).collect();
+
+    // Fewer than three rim nodes would join a node to itself or pair twice.
+    let ring = count >= 3;
+    let edge = 2.0 * SPACING * (TAU / 2.0 / count as f32).sin();
+    for (i, (a, b)) in rim.iter().zip(rim.iter().cycle().skip(1)).enumerate() {
+        if ring { parent.spawn(springs.soft.spring(*a, *b, edge)); }
+        control(parent, springs, center, *a, i + 1, alternate(i));
+    }
+    if pressure > 0.0 {
+        parent.spawn(Pressure { nodes: rim.clone(), area: signed_area(&points).abs(), stiffness: pressure });
+    }
+
+    std::iter::once(vec![center])
+        .chain(rim.iter().map(|node| vec![*node]))
+        .enumerate()
+        .map(|(index, nodes)| Segment { index, nodes })
+        .collect()
+}
+
+fn grid(
+    parent: &mut ChildBuilder,
+    springs: &SpringClasses,
+    position: Vec3,
+    width: usize,
+    height: usize
+) -> Vec<Segment<Entity>> {
+    let middle = (height as f32 - 1.0) / 2.0;
+    let mut columns: Vec<Vec<Entity>> = vec![];
+    for c in 0..width {
+        let column = (0..height)
+            .map(|r| {
+                let offset = Vec3::new(-(c as f32), r as f32 - middle, 0.0) * SPACING;
+                let mut node = spawn_node(parent, &NodeSpec::default(), position + offset);
+                if c == 0 && r == height / 2 { node.insert(Head); }
+                node.id()
+            })
+            .collect();
+        columns.push(column);
+    }
+
+    for column in &columns {
+        for pair in column.windows(2) {
+            parent.spawn(springs.soft.spring(pair[0], pair[1], SPACING));
+        }
+    }
+    let diagonal = SPACING * std::f32::consts::SQRT_2;
+    for (c, pair) in columns.windows(2).enumerate() {
+        let (front, back) = (&pair[0], &pair[1]);
+        for (r, (a, b)) in back.iter().zip(front).enumerate() {
+            match (2 * r + 1).cmp(&height) {
+                Ordering::Less => control(parent, springs, *a, *b, c + 1, -1.0),
+                Ordering::Greater => control(parent, springs, *a, *b, c + 1, 1.0),
+                Ordering::Equal => { parent.spawn(springs.skeleton.spring(*a, *b, SPACING)); }
+            }
+        }
+        for (b, f) in back.windows(2).zip(front.windows(2)) {
+            parent.spawn((springs.brace.spring(b[1], f[0], diagonal), SpringHidden));
+            parent.spawn((springs.brace.spring(b[0], f[1], diagonal), SpringHidden));
+        }
+    }
+
+    columns.into_iter()
+        .enumerate()
+        .map(|(index, nodes)| Segment { index, nodes })
+        .collect()
+}
+
+fn star(
+    parent: &mut ChildBuilder,
+    springs: &SpringClasses,
+    position: Vec3,
+    count: usize,
+    length: usize
+) -> Vec<Segment<Entity>> {
+    let center = spawn_node(parent, &NodeSpec::default(), position).insert(Head).id();
+    let arms: Vec<Vec<Entity>> = (0..count)
+        .map(|i| {
+            let angle = i as f32 / count as f32 * TAU;
+            let direction = Vec3::new(angle.cos(), angle.sin(), 0.0);
+            (1..=length).map(|j| node(parent, position + direction * SPACING * j as f32)).collect()
+        })
+        .collect();
+
+    let edge = 2.0 * SPACING * (TAU / 2.0 / count as f32).sin();
+    for (i, arm) in arms.iter().enumerate() {
+        if arm.is_empty() { continue }
+        parent.spawn(springs.skeleton.spring(center, arm[0], SPACING));
+        for pair in arm.windows(2) {
+            control(parent, springs, pair[0], pair[1], i + 1, alternate(i));
+        }
+        if count < 3 { continue }
+        if let Some(next) = arms[(i + 1) % count].first() {
+            parent.spawn(springs.soft.spring(arm[0], *next, edge));
+        }
+    }
+
+    std::iter::once(vec![center])
+        .chain(arms)
+        .enumerate()
+        .map(|(index, nodes)| Segment { index, nodes })
+        .collect()
+}
+
+/// Spawns a blob the same way `worm_builder` spawns a worm.
+pub fn blob_builder(
+    shape: &BlobShape,
+    position: Vec3,
+    commands: &mut Commands,
+    controller: fn(f32, f32, f32) -> f32,
+    neurons: usize,
+    brain: CTRNN,
+    springs: &SpringClasses
+) -> Entity {
+    let mut parts = vec![];
+    let parent_id = commands.spawn((
+        Transform::default(),
+        GlobalTransform::default(),
+        VisibilityBundle::default(),
+        brain,
+        Inputs::default(),
+        UpdateFlux,
+        Neurons(vec![0.0; neurons]),
+        Blob
+    )).with_children(|parent| {
+        parts = match *shape {
+            BlobShape::Radial { nodes, pressure } => radial(parent, springs, position, nodes, pressure),
+            BlobShape::Grid { width, height } => grid(parent, springs, position, width, height),
+            BlobShape::Star { arms, length } => star(parent, springs, position, arms, length),
+        };
+    }).id();
+
+    commands.entity(parent_id).insert(WormController::new(controller, parts));
+
+    parent_id
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::CommandQueue;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    fn springs_of(shape: BlobShape) -> Vec<(Entity, Entity)> {
+        let mut world = World::new();
+        let mut queue = CommandQueue::default();
+        let brain = CTRNN::trained(false, &mut ChaCha8Rng::seed_from_u64(0));
+        let mut commands = Commands::new(&mut queue, &world);
+        blob_builder(&shape, Vec3::ZERO, &mut commands, |_, _, _| 0.0, 1, brain, &SpringClasses::default());
+        queue.apply(&mut world);
+        world.query::<&Spring>().iter(&world).map(|spring| (spring.a, spring.b)).collect()
+    }
+
+    #[test]
+    fn small_blobs_spawn_no_self_springs() {
+        for shape in [BlobShape::Star { arms: 1, length: 2 }, BlobShape::Star { arms: 2, length: 1 },
+            BlobShape::Radial { nodes: 1, pressure: 0.0 }, BlobShape::Radial { nodes: 2, pressure: 0.0 }]
+        {
+            let springs = springs_of(shape.clone());
+            assert!(springs.iter().all(|(a, b)| a != b), "{:?}", shape);
+            for (i, (a, b)) in springs.iter().enumerate() {
+                assert!(!springs[i + 1..].iter().any(|&pair| pair == (*a, *b) || pair == (*b, *a)), "{:?}", shape);
+            }
+        }
+    }
+
+    #[test]
+    fn rings_close_once_there_are_three() {
+        // Three spokes and three rim springs.
+        assert_eq!(springs_of(BlobShape::Radial { nodes: 3, pressure: 0.0 }).len(), 6);
+        // Per arm: a root, a driven spring and a ring spring.
+        assert_eq!(springs_of(BlobShape::Star { arms: 3, length: 2 }).len(), 9);
+    }
+
+    #[test]
+    fn degenerate_shapes_are_rejected() {
+        assert!(BlobShape::Radial { nodes: 2, pressure: 0.0 }.check().is_err());
+        assert!(BlobShape::Radial { nodes: 3, pressure: 0.0 }.check().is_ok());
+        assert!(BlobShape::Star { arms: 1, length: 3 }.check().is_err());
+        assert!(BlobShape::Star { arms: 3, length: 0 }.check().is_err());
+        assert!(BlobShape::Star { arms: 2, length: 1 }.check().is_ok());
+        assert!(BlobShape::Grid { width: 0, height: 3 }.check().is_err());
+        assert!(BlobShape::Grid { width: 1, height: 1 }.check().is_ok());
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    physics::*,
+    pressure::{signed_area, Pressure},
+    sensors::ContactSensor,
+    worm::{Control, Head, Index, Segment, Side, SpringClasses, SpringHidden},
+};
+
+const DRAG_NODE: f32 = 0.0;
+pub const DRAG_EDGE: f32 = 1.0;
+/// Skeleton springs carry the body's resistive force theory friction.
+const RFT_SKELETON: Rft = Rft { tangential: 1.0, normal: 1.0 };
+const SCALE: f32 = 0.5;
+/// Matches the size nodes are drawn at.
+const NODE_RADIUS: f32 = 0.1;
+
+/// One node of the head or of every segment. `offset` is in body units, `x`
+/// running forwards along the body and `y` across it, negative on the left.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeSpec {
+    pub name: String,
+    pub offset: [f32; 2],
+    pub mass: f32,
+    pub drag: f32,
+    pub radius: f32,
+    /// Whether the node carries a `ContactSensor`.
+    pub contact: bool,
+}
+
+impl Default for NodeSpec {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            offset: [0.0, 0.0],
+            mass: 1.0,
+            drag: DRAG_NODE,
+            radius: NODE_RADIUS,
+            contact: false,
+        }
+    }
+}
+
+impl NodeSpec {
+    fn new(name: &str, offset: [f32; 2], contact: bool) -> Self {
+        Self { name: name.to_string(), offset, contact, ..default() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpringKind {
+    Skeleton,
+    Soft,
+    Brace,
+    Control,
+}
+
+/// A spring between two nodes named `name` for the segment being built or
+/// `prev.name` for the one in front of it, the head for the first segment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpringSpec {
+    pub a: String,
+    pub b: String,
+    pub class: SpringKind,
+    /// Rest length in body units.
+    pub length: f32,
+    /// Makes the spring one the brain drives, on the given side.
+    #[serde(default)]
+    pub control: Option<Side>,
+    /// Left out when drawing.
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub drag: Option<f32>,
+    /// Whether the spring takes part in resistive force theory friction.
+    #[serde(default)]
+    pub rft: bool,
+}
+
+impl SpringSpec {
+    fn new(a: &str, b: &str, class: SpringKind, length: f32) -> Self {
+        Self { a: a.to_string(), b: b.to_string(), class, length, control: None, hidden: false, drag: None, rft: false }
+    }
+}
+
+/// The layout of a body: a head followed by repeated segments, each segment
+/// joined to the one in front of it. Both spawning and developmental growth
+/// build bodies from it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BodyPlan {
+    /// World units per body unit.
+    pub scale: f32,
+    /// Body units between consecutive segments.
+    pub spacing: f32,
+    pub head: Vec<NodeSpec>,
+    /// Nodes of every segment. The first carries the segment's `Index`.
+    pub nodes: Vec<NodeSpec>,
+    /// Springs joining the first segment to the head.
+    pub neck: Vec<SpringSpec>,
+    /// Springs within every segment.
+    pub segment: Vec<SpringSpec>,
+    /// Springs joining every later segment to the one in front of it.
+    pub joint: Vec<SpringSpec>,
+    /// Closed rings of nodes, named as in `joint`, whose area every later
+    /// segment holds with gas `pressure`.
+    pub rings: Vec<Vec<String>>,
+    /// Stiffness of the gas in the rings, left out altogether when 0.
+    pub pressure: f32,
+}
+
+impl Default for BodyPlan {
+    /// The worm: a center line with a node to each side, braced across and
+    /// driven along both sides.
+    fn default() -> Self {
+        let control = |a: &str, b: &str, side| SpringSpec {
+            control: Some(side),
+            drag: Some(DRAG_EDGE),
+            ..SpringSpec::new(a, b, SpringKind::Control, 1.0)
+        };
+        Self {
+            scale: SCALE,
+            spacing: 1.0,
+            head: vec![NodeSpec::new("head", [0.0, 0.0], true)],
+            nodes: vec![
+                NodeSpec::new("center", [-1.0, 0.0], false),
+                NodeSpec::new("left", [-0.5, -0.5], true),
+                NodeSpec::new("right", [-0.5, 0.5], true),
+            ],
+            neck: vec![
+                SpringSpec::new("left", "prev.head", SpringKind::Soft, 1.0),
+                SpringSpec { rft: true, ..SpringSpec::new("center", "prev.head", SpringKind::Skeleton, 1.0) },
+                SpringSpec::new("right", "prev.head", SpringKind::Soft, 1.0),
+            ],
+            segment: vec![
+                SpringSpec::new("center", "left", SpringKind::Soft, 1.0),
+                SpringSpec::new("center", "right", SpringKind::Soft, 1.0),
+            ],
+            joint: vec![
+                SpringSpec { rft: true, ..SpringSpec::new("center", "prev.center", SpringKind::Skeleton, 1.0) },
+                SpringSpec::new("left", "prev.center", SpringKind::Soft, 1.0),
+                SpringSpec::new("right", "prev.center", SpringKind::Soft, 1.0),
+                SpringSpec { hidden: true, ..SpringSpec::new("left", "right", SpringKind::Brace, 2.0) },
+                control("left", "prev.left", Side::Left),
+                control("right", "prev.right", Side::Right),
+            ],
+            rings: vec![["prev.left", "left", "right", "prev.right"].map(String::from).to_vec()],
+            pressure: 0.0,
+        }
+    }
+}
+
+impl BodyPlan {
+    /// Checks that names are unique and every node a spring or ring names
+    /// exists where `spawn_segment` looks it up, so spawning cannot fail.
+    pub fn check(&self) -> Result<(), String> {
+        if self.head.is_empty() || self.nodes.is_empty() {
+            return Err("a body plan needs head and segment nodes".to_string());
+        }
+        for group in [&self.head, &self.nodes] {
+            let mut names = HashSet::new();
+            if let Some(node) = group.iter().find(|node| !names.insert(&node.name)) {
+                return Err(format!("body plan node `{}` is defined twice", node.name));
+            }
+        }
+        // Springs within a segment have no `prev` to refer to.
+        let none = vec![];
+        let springs = [(&self.neck, &self.head), (&self.segment, &none), (&self.joint, &self.nodes)];
+        for (springs, prev) in springs {
+            for spring in springs {
+                for name in [&spring.a, &spring.b] {
+                    if self.node(name, prev).is_none() {
+                        return Err(format!("body plan spring refers to unknown node `{}`", name));
+                    }
+                }
+            }
+        }
+        for ring in &self.rings {
+            if ring.len() < 3 {
+                return Err("body plan rings need at least three nodes".to_string());
+            }
+            if let Some(name) = ring.iter().find(|name| self.node(name, &self.nodes).is_none()) {
+                return Err(format!("body plan ring refers to unknown node `{}`", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Rest area of a ring, from the node offsets with the segment in front
+    /// `spacing` ahead.
+    fn ring_area(&self, ring: &[String]) -> f32 {
+        let points: Vec<Vec3> = ring.iter()
+            .map(|name| match self.node(name, &self.nodes) {
+                Some((true, i)) => Vec2::from_array(self.nodes[i].offset) + Vec2::new(self.spacing, 0.0),
+                Some((false, i)) => Vec2::from_array(self.nodes[i].offset),
+                None => Vec2::ZERO,
+            })
+            .map(|point| (point * self.scale).extend(0.0))
+            .collect();
+        signed_area(&points).abs()
+    }
+
+    /// Index of a segment node by name.
+    pub fn index(&self, name: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.name == name)
+    }
+
+    /// Where `name` is: `(true, i)` for the i-th node of `prev`, `(false, i)`
+    /// for the i-th node of the segment being built.
+    fn node(&self, name: &str, prev: &[NodeSpec]) -> Option<(bool, usize)> {
+        match name.strip_prefix("prev.") {
+            Some(name) => prev.iter().position(|node| node.name == name).map(|i| (true, i)),
+            None => self.index(name).map(|i| (false, i)),
+        }
+    }
+
+    /// Where each node of segment `index` sits when the body is laid out
+    /// straight along -x from `position`.
+    pub fn layout(&self, index: usize, position: Vec3) -> Vec<Vec3> {
+        let back = index as f32 * self.spacing;
+        self.nodes.iter()
+            .map(|node| position + Vec3::new(node.offset[0] - back, node.offset[1], 0.0) * self.scale)
+            .collect()
+    }
+
+    /// Where the nodes of a new segment go behind `last`, the positions of the
+    /// current tail segment, following the direction from `prev` to it.
+    pub fn grow(&self, prev: &[Vec3], last: &[Vec3]) -> Vec<Vec3> {
+        let mean = |points: &[Vec3]| points.iter().copied().sum::<Vec3>() / points.len() as f32;
+        let forward = (mean(prev) - mean(last)).truncate().try_normalize().unwrap_or(Vec2::X);
+        let side = forward.perp();
+        let offsets: Vec<Vec2> = self.nodes.iter().map(|node| Vec2::from_array(node.offset)).collect();
+        let center = offsets.iter().copied().sum::<Vec2>() / offsets.len() as f32;
+        let tail = mean(last);
+        offsets.iter()
+            .map(|offset| *offset - center - Vec2::new(self.spacing, 0.0))
+            .map(|local| tail + ((forward * local.x + side * local.y) * self.scale).extend(0.0))
+            .collect()
+    }
+
+    /// Spawns the head's nodes, the first carrying `Head`.
+    pub fn spawn_head(&self, parent: &mut ChildBuilder, position: Vec3) -> Vec<Entity> {
+        self.head.iter()
+            .enumerate()
+            .map(|(i, spec)| {
+                let position = position + Vec2::from_array(spec.offset).extend(0.0) * self.scale;
+                let mut node = spawn_node(parent, spec, position);
+                if i == 0 { node.insert(Head); }
+                node.id()
+            })
+            .collect()
+    }
+
+    /// Spawns segment `index` at `positions` with its springs, joined to
+    /// `prev`: the head's nodes for segment 0, the segment in front otherwise.
+    pub fn spawn_segment(
+        &self,
+        parent: &mut ChildBuilder,
+        classes: &SpringClasses,
+        index: usize,
+        positions: &[Vec3],
+        prev: &[Entity]
+    ) -> Segment<Entity> {
+        let nodes: Vec<Entity> = self.nodes.iter()
+            .zip(positions)
+            .enumerate()
+            .map(|(i, (spec, position))| {
+                let mut node = spawn_node(parent, spec, *position);
+                if i == 0 { node.insert(Index(index)); }
+                node.id()
+            })
+            .collect();
+
+        let (joints, prev_specs) = if index == 0 { (&self.neck, &self.head) } else { (&self.joint, &self.nodes) };
+        let find = |name: &str| match self.node(name, prev_specs) {
+            Some((true, i)) => prev[i],
+            Some((false, i)) => nodes[i],
+            None => unreachable!("`BodyPlan::check` lets no unknown node `{}` through", name),
+        };
+        for spec in joints.iter().chain(&self.segment) {
+            let class = match spec.class {
+                SpringKind::Skeleton => &classes.skeleton,
+                SpringKind::Soft => &classes.soft,
+                SpringKind::Brace => &classes.brace,
+                SpringKind::Control => &classes.control,
+            };
+            let mut spring = parent.spawn(class.spring(find(&spec.a), find(&spec.b), spec.length * self.scale));
+            if let Some(side) = spec.control {
+                spring.insert(Control { index: index as i32, side: side.sign() });
+            }
+            if spec.hidden { spring.insert(SpringHidden); }
+            if let Some(drag) = spec.drag { spring.insert(Drag(drag)); }
+            if spec.rft { spring.insert(RFT_SKELETON); }
+        }
+
+        if index > 0 && self.pressure > 0.0 {
+            for ring in &self.rings {
+                parent.spawn(Pressure {
+                    nodes: ring.iter().map(|name| find(name)).collect(),
+                    area: self.ring_area(ring),
+                    stiffness: self.pressure,
+                });
+            }
+        }
+
+        Segment { index, nodes }
+    }
+}
+
+pub fn spawn_node<'w, 's, 'a>(
+    parent: &'a mut ChildBuilder<'w, 's, '_>,
+    spec: &NodeSpec,
+    position: Vec3
+) -> bevy::ecs::system::EntityCommands<'w, 's, 'a> {
+    let mut node = parent.spawn((
+        Position::new(position),
+        Force::default(),
+        Mass(spec.mass),
+        Drag(spec.drag),
+        Radius(spec.radius)
+    ));
+    if spec.contact { node.insert(ContactSensor::default()); }
+    node
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spring(a: &str, b: &str) -> SpringSpec {
+        SpringSpec::new(a, b, SpringKind::Soft, 1.0)
+    }
+
+    #[test]
+    fn default_plan_passes() {
+        assert!(BodyPlan::default().check().is_ok());
+    }
+
+    #[test]
+    fn missing_and_duplicate_nodes_are_rejected() {
+        let no_head = BodyPlan { head: vec![], ..default() };
+        assert!(no_head.check().is_err());
+        let mut twice = BodyPlan::default();
+        twice.nodes.push(NodeSpec::new("left", [0.0, 0.0], false));
+        assert!(twice.check().is_err());
+    }
+
+    #[test]
+    fn springs_must_name_nodes_where_they_are_built() {
+        let bad = [
+            BodyPlan { neck: vec![spring("center", "prev.tail")], ..default() },
+            BodyPlan { neck: vec![spring("center", "prev.center")], ..default() },
+            BodyPlan { segment: vec![spring("center", "prev.center")], ..default() },
+            BodyPlan { segment: vec![spring("center", "middle")], ..default() },
+            BodyPlan { joint: vec![spring("center", "prev.head")], ..default() },
+        ];
+        for plan in bad {
+            assert!(plan.check().is_err());
+        }
+        let good = BodyPlan { neck: vec![spring("left", "prev.head")], ..default() };
+        assert!(good.check().is_ok());
+    }
+
+    #[test]
+    fn rings_must_be_closed_and_known() {
+        let short = BodyPlan { rings: vec![vec!["left".to_string(), "right".to_string()]], ..default() };
+        assert!(short.check().is_err());
+        let unknown = ["prev.left", "left", "tail"].map(String::from).to_vec();
+        assert!(BodyPlan { rings: vec![unknown], ..default() }.check().is_err());
+    }
+}
@@ -1,15 +1,41 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, fs, ops::Range, path::Path};
 
 use ctrnn::RLCTRNN;
 
-use bevy::prelude::*;
+use bevy::{app::AppExit, prelude::*};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 
-use crate::HISTORY_LENGTH;
+use crate::{config::Config, step::{SimulationStage, Timestep}, worm::WormId};
+
+/// Range starting voltages are drawn from with the run's seeded generator.
+/// This stands in for `RLCTRNN::init_voltage`, which draws from the crate's
+/// own unseeded generator and so cannot be replayed; its distribution is not
+/// public, so seeded runs may start from different voltages than before.
+const INITIAL_VOLTAGE: Range<f64> = -1.0..1.0;
 
 #[derive(Component)]
 pub struct UpdateFlux;
-#[derive(Component)]
-pub struct LogCTRNN;
+
+/// Systems adding sensor readings to `Inputs` before the brain steps.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct Sensing;
+
+/// Systems stepping the brains, after `Sensing` and before `Actuation`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct Thinking;
+
+/// External input to each neuron, summed by the sensors every step and
+/// cleared once the brain has used it.
+#[derive(Component, Debug, Clone, Default)]
+pub struct Inputs(pub Vec<f64>);
+
+impl Inputs {
+    pub fn add(&mut self, neuron: usize, value: f64) {
+        if neuron >= self.0.len() { self.0.resize(neuron + 1, 0.0); }
+        self.0[neuron] += value;
+    }
+}
 
 #[derive(Component)]
 pub struct CTRNN {
@@ -24,11 +50,31 @@ pub struct CTRNN {
 }
 
 impl CTRNN {
+    pub fn new(ctrnn: RLCTRNN, voltages: Vec<f64>) -> Self {
+        Self {
+            ctrnn,
+            voltages,
+            output_history: VecDeque::new(),
+            flux_history: vec![],
+            activity_history: vec![],
+            fitness_history: vec![],
+            fitness_sum: vec![],
+            avg_fitness_sum: vec![]
+        }
+    }
+
+    /// The hand-tuned network with voltages drawn from `rng`.
+    pub fn trained(devo_brain: bool, rng: &mut impl Rng) -> Self {
+        let ctrnn = Self::trained_ctrnn(devo_brain);
+        let voltages = (0..ctrnn.count).map(|_| rng.gen_range(INITIAL_VOLTAGE)).collect();
+        Self::new(ctrnn, voltages)
+    }
+
     pub fn get_outputs(&self) -> Vec<f64> {
         self.ctrnn.get_outputs(&self.voltages)
     }
 
-    pub fn trained_ctrnn() -> RLCTRNN {
+    pub fn trained_ctrnn(devo_brain: bool) -> RLCTRNN {
         let mut ctrnn = ctrnn::RLCTRNN::new(2);
         ctrnn
             .set_bias(0, -2.75)
@@ -45,7 +91,7 @@ impl CTRNN {
             }
         }
 
-        if !crate::DEVO_BRAIN {
+        if !devo_brain {
             for _ in 0..8 { ctrnn.add_node(); }
         }
 
@@ -53,20 +99,105 @@ impl CTRNN {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FluxSnapshot {
+    pub center: f64,
+    pub period: (f64, f64),
+}
 
-fn ctrnn_update(mut ctrnns: Query<&mut CTRNN>) {
-    for mut ctrnn in ctrnns.iter_mut() {
+/// Everything needed to rebuild an `RLCTRNN` and its state, stored as TOML.
+/// Weights are flattened row by row, `weights[to * count + from]`. The
+/// fluctuators' current phase lives inside `ctrnn` and is not captured.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrainSnapshot {
+    pub count: usize,
+    pub voltages: Vec<f64>,
+    pub biases: Vec<FluxSnapshot>,
+    pub weights: Vec<FluxSnapshot>,
+}
+
+impl BrainSnapshot {
+    pub fn capture(ctrnn: &CTRNN) -> Self {
+        let net = &ctrnn.ctrnn;
+        let flux = |center: f64, period: &Range<f64>| FluxSnapshot { center, period: (period.start, period.end) };
+        Self {
+            count: net.count,
+            voltages: ctrnn.voltages.clone(),
+            biases: (0..net.count)
+                .map(|i| flux(net.biases[i].center, &net.biases[i].range_period))
+                .collect(),
+            weights: (0..net.count)
+                .flat_map(|to| (0..net.count).map(move |from| (to, from)))
+                .map(|(to, from)| flux(net.weights[to][from].center, &net.weights[to][from].range_period))
+                .collect(),
+        }
+    }
+
+    pub fn restore(&self) -> CTRNN {
+        let mut net = RLCTRNN::new(self.count);
+        for (i, bias) in self.biases.iter().enumerate() {
+            net.biases[i].center = bias.center;
+            net.biases[i].range_period = bias.period.0..bias.period.1;
+        }
+        for (i, weight) in self.weights.iter().enumerate() {
+            let (to, from) = (i / self.count, i % self.count);
+            net.weights[to][from].center = weight.center;
+            net.weights[to][from].range_period = weight.period.0..weight.period.1;
+        }
+        CTRNN::new(net, self.voltages.clone())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let snapshot: Self = toml::from_str(&text)
+            .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+        let count = snapshot.count;
+        if snapshot.voltages.len() != count || snapshot.biases.len() != count
+            || snapshot.weights.len() != count * count
+        {
+            return Err(format!(
+                "{}: a brain of {} neurons needs {} voltages, {} biases and {} weights",
+                path.display(), count, count, count, count * count
+            ));
+        }
+        Ok(snapshot)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let text = toml::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(path, text).map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+}
+
+
+fn ctrnn_update(mut ctrnns: Query<(&mut CTRNN, Option<&mut Inputs>)>, timestep: Res<Timestep>) {
+    for (mut ctrnn, inputs) in ctrnns.iter_mut() {
         let voltages = &ctrnn.voltages.clone();
-        ctrnn.voltages = ctrnn.ctrnn.update(0.05, voltages, vec![]);
+        let inputs = match inputs {
+            Some(mut inputs) => {
+                let mut values = vec![0.0; ctrnn.ctrnn.count];
+                for (value, input) in values.iter_mut().zip(inputs.0.iter_mut()) {
+                    *value = *input;
+                }
+                inputs.0.iter_mut().for_each(|input| *input = 0.0);
+                values
+            }
+            None => vec![],
+        };
+        ctrnn.voltages = ctrnn.ctrnn.update(timestep.brain_dt(), voltages, inputs);
     }
 }
 
-fn ctrnn_history(mut ctrnns: Query<&mut CTRNN>) {
+fn ctrnn_history(mut ctrnns: Query<&mut CTRNN>, config: Res<Config>) {
+    let history_length = config.history_length;
     for mut ctrnn in ctrnns.iter_mut() {
         let default: Vec<f64> = vec![];
         let outputs = ctrnn.get_outputs();
         ctrnn.output_history.push_back(outputs);
-        if ctrnn.output_history.len() > HISTORY_LENGTH {
+        if ctrnn.output_history.len() > history_length {
             ctrnn.output_history.pop_front();
         }
 
@@ -88,16 +219,16 @@ fn ctrnn_history(mut ctrnns: Query<&mut CTRNN>) {
 
             ctrnn.activity_history[to].push_back(activity);
             ctrnn.fitness_sum[to] += activity;
-            if ctrnn.activity_history[to].len() > HISTORY_LENGTH {
+            if ctrnn.activity_history[to].len() > history_length {
                 if let Some(value) = ctrnn.activity_history[to].pop_front() {
                     ctrnn.fitness_sum[to] -= value;
                 }
             }
-            let fitness = ctrnn.fitness_sum[to] / HISTORY_LENGTH as f64;
+            let fitness = ctrnn.fitness_sum[to] / history_length as f64;
 
             ctrnn.fitness_history[to].push_back(fitness);
             ctrnn.avg_fitness_sum[to] += fitness;
-            if ctrnn.fitness_history[to].len() > HISTORY_LENGTH {
+            if ctrnn.fitness_history[to].len() > history_length {
                 if let Some(value) = ctrnn.fitness_history[to].pop_front() {
                     ctrnn.avg_fitness_sum[to] -= value
                 }
@@ -110,7 +241,7 @@ fn ctrnn_history(mut ctrnns: Query<&mut CTRNN>) {
                 let center = ctrnn.ctrnn.weights[to][from].center;
                 let value = ctrnn.ctrnn.weights[to][from].get();
                 ctrnn.flux_history[to][from].push_back((center, value));
-                if ctrnn.flux_history[to][from].len() > HISTORY_LENGTH {
+                if ctrnn.flux_history[to][from].len() > history_length {
                     ctrnn.flux_history[to][from].pop_front();
                 }
             }
@@ -118,46 +249,55 @@ fn ctrnn_history(mut ctrnns: Query<&mut CTRNN>) {
     }
 }
 
-fn fluctuator_update(mut ctrnns: Query<&mut CTRNN, With<UpdateFlux>>) {
+fn fluctuator_update(
+    mut ctrnns: Query<&mut CTRNN, With<UpdateFlux>>,
+    config: Res<Config>,
+    timestep: Res<Timestep>
+) {
+    let history_length = config.history_length;
+    let dt = timestep.tick() as f64;
     for mut ctrnn in ctrnns.iter_mut() {
         for to in 0..ctrnn.ctrnn.count {
-            let fitness = ctrnn.fitness_sum.get(to).unwrap_or(&0.0) / HISTORY_LENGTH as f64;
-            let avg_fitness = ctrnn.avg_fitness_sum.get(to).unwrap_or(&0.0) / HISTORY_LENGTH as f64;
+            let fitness = ctrnn.fitness_sum.get(to).unwrap_or(&0.0) / history_length as f64;
+            let avg_fitness = ctrnn.avg_fitness_sum.get(to).unwrap_or(&0.0) / history_length as f64;
             for from in 0..ctrnn.ctrnn.count {
                 let f = &mut ctrnn.ctrnn.weights[to][from];
-                f.update(1.0 / 60.0, fitness - avg_fitness);
+                f.update(dt, fitness - avg_fitness);
             }
         }
     }
 }
 
-fn log_ctrnn(ctrnns: Query<&CTRNN, With<LogCTRNN>>) {
-    for ctrnn in ctrnns.iter() {
-        let outputs = ctrnn.get_outputs();
-        println!("{:.3?}", outputs);
+fn add_neuron(mut worms: Query<(&mut CTRNN, &mut crate::Adder)>) {
+    for (mut ctrnn, mut adder) in worms.iter_mut() {
+        if adder.neuron > 0 {
+            adder.neuron -= 1;
+            ctrnn.ctrnn.add_node();
+            ctrnn.voltages.push(0.0);
+        }
     }
 }
 
-fn add_neuron(
-    mut ctrnns: Query<&mut CTRNN>,
-    mut adder: ResMut<crate::Adder>
+fn save_brain_on_exit(
+    mut exit: EventReader<AppExit>,
+    config: Res<Config>,
+    ctrnns: Query<(&WormId, &CTRNN)>
 ) {
-    if adder.neuron > 0 {
-        adder.neuron -= 1;
-        for mut ctrnn in ctrnns.iter_mut() {
-            ctrnn.ctrnn.add_node();
-            ctrnn.voltages.push(0.0);
-        }
+    if exit.iter().next().is_none() { return }
+    let first = ctrnns.iter().min_by_key(|(id, _)| **id).map(|(_, ctrnn)| ctrnn);
+    let (Some(path), Some(ctrnn)) = (&config.save_brain, first) else { return };
+    if let Err(err) = BrainSnapshot::capture(ctrnn).save(path) {
+        eprintln!("{}", err);
     }
 }
 
 pub struct BrainPlugin;
 impl Plugin for BrainPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(ctrnn_update);
-        app.add_system(ctrnn_history);
-        app.add_system(fluctuator_update.after(ctrnn_update));
-        app.add_system(log_ctrnn);
-        app.add_system(add_neuron);
+        app.add_system_to_stage(SimulationStage, add_neuron.after(crate::devo_timer).before(Sensing));
+        app.add_system_to_stage(SimulationStage, ctrnn_update.label(Thinking).after(Sensing));
+        app.add_system_to_stage(SimulationStage, ctrnn_history.label(Thinking).after(ctrnn_update));
+        app.add_system_to_stage(SimulationStage, fluctuator_update.label(Thinking).after(ctrnn_history));
+        app.add_system_to_stage(CoreStage::Last, save_brain_on_exit);
     }
 }
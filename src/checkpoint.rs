@@ -0,0 +1,480 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use bevy::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blob::Blob,
+    brain::{BrainSnapshot, Inputs, UpdateFlux, CTRNN},
+    config::Config,
+    fitness::{ControlLengths, Fitnesses, Persist},
+    muscle::Muscle,
+    physics::*,
+    pressure::Pressure,
+    sensors::{ContactSensor, PreferredTemperature},
+    worm::*,
+    Adder, Development, InitialPosition, SimRng, TimeTracker, TimeTracker2, TimeTrackerInt,
+};
+
+#[derive(Serialize, Deserialize)]
+struct BrainState {
+    snapshot: BrainSnapshot,
+    output_history: VecDeque<Vec<f64>>,
+    flux_history: Vec<Vec<VecDeque<(f64, f64)>>>,
+    activity_history: Vec<VecDeque<f64>>,
+    fitness_history: Vec<VecDeque<f64>>,
+    fitness_sum: Vec<f64>,
+    avg_fitness_sum: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WormState {
+    id: usize,
+    translation: [f32; 3],
+    initial: Option<[f32; 3]>,
+    adder: (usize, usize),
+    development: (bool, bool),
+    preferred_temperature: f32,
+    blob: bool,
+    cyclical: bool,
+    regional: bool,
+    frequency: Option<(f32, f32)>,
+    manual: bool,
+    update_flux: bool,
+    neurons: Vec<f32>,
+    brain: BrainState,
+    /// Name and running totals of each fitness, in `Config::fitness` order.
+    fitnesses: Vec<(String, Vec<u8>)>,
+    /// `(index, nodes)`, the nodes as indices into `nodes`.
+    segments: Vec<(usize, Vec<u32>)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NodeState {
+    worm: u32,
+    now: [f32; 3],
+    last: [f32; 3],
+    mass: f32,
+    drag: Option<f32>,
+    radius: Option<f32>,
+    index: Option<usize>,
+    head: bool,
+    /// Penetration of the node's `ContactSensor`, if it has one.
+    contact: Option<f32>,
+    locked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SpringState {
+    worm: u32,
+    a: u32,
+    b: u32,
+    constant: f32,
+    length: f32,
+    control: Option<(i32, f32)>,
+    hidden: bool,
+    drag: Option<f32>,
+    rft: Option<(f32, f32)>,
+    material: Option<Material>,
+    muscle: Option<Muscle>,
+    /// Length the efficiency fitness measures the next tick's work from.
+    last_length: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PressureState {
+    worm: u32,
+    nodes: Vec<u32>,
+    area: f32,
+    stiffness: f32,
+}
+
+/// Everything that evolves during a run, except the state the `ctrnn` crate
+/// keeps inside each fluctuator. Nodes and springs are kept in query order
+/// and respawned in that order, so force accumulation sums in the same order
+/// after a resume.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub config: Config,
+    time: f32,
+    time_t: f32,
+    time_int: i32,
+    rng: ChaCha8Rng,
+    worms: Vec<WormState>,
+    nodes: Vec<NodeState>,
+    springs: Vec<SpringState>,
+    pressures: Vec<PressureState>,
+}
+
+impl Checkpoint {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+        bincode::deserialize_from(BufReader::new(file))
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))
+    }
+
+    /// Writes next to `path` first and renames, so a crash never leaves a
+    /// half-written checkpoint behind.
+    /// Restores every worm's fitness totals, so a file that cannot resume is
+    /// refused by `Config::with_checkpoint` before anything is built.
+    pub fn check(&self, config: &Config) -> Result<(), String> {
+        for worm in &self.worms {
+            restore_fitnesses(config, &worm.fitnesses)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let file = File::create(&tmp).map_err(|e| format!("could not create {}: {}", tmp.display(), e))?;
+        bincode::serialize_into(BufWriter::new(file), self).map_err(|e| e.to_string())?;
+        fs::rename(&tmp, path).map_err(|e| format!("could not write {}: {}", path.display(), e))
+    }
+}
+
+/// The fitnesses `config` selects, each picking up its saved totals when one
+/// of the same name was saved in its place. Fitnesses selected anew on the
+/// command line start from scratch.
+fn restore_fitnesses(config: &Config, saved: &[(String, Vec<u8>)]) -> Result<Fitnesses, String> {
+    let mut fitnesses = Fitnesses::from_config(config);
+    for (fitness, (name, state)) in fitnesses.0.iter_mut().zip(saved) {
+        if fitness.name() != name { continue }
+        fitness.restore(state).map_err(|err| format!("corrupt {} fitness in checkpoint: {}", name, err))?;
+    }
+    Ok(fitnesses)
+}
+
+/// Experiment time the last checkpoint was written at, or resumed from.
+#[derive(Resource, Default)]
+struct LastCheckpoint(f32);
+
+type WormQuery<'a> = (
+    Entity,
+    &'a WormId,
+    &'a InitialPosition,
+    &'a Adder,
+    &'a Development,
+    &'a PreferredTemperature,
+    &'a WormController,
+    &'a CTRNN,
+    &'a Neurons,
+    &'a Transform,
+    Option<&'a CyclicalMapping>,
+    Option<&'a RegionalMapping>,
+    Option<&'a FrequencyMapping>,
+    Option<&'a ManualControl>,
+    Option<&'a UpdateFlux>,
+);
+
+type NodeQuery<'a> = (
+    Entity,
+    &'a Parent,
+    &'a Position,
+    &'a Mass,
+    Option<&'a Drag>,
+    Option<&'a Radius>,
+    Option<&'a Index>,
+    Option<&'a Head>,
+    Option<&'a ContactSensor>,
+    Option<&'a Locked>,
+);
+
+type SpringQuery<'a> = (
+    Entity,
+    &'a Parent,
+    &'a Spring,
+    Option<&'a Control>,
+    Option<&'a SpringHidden>,
+    Option<&'a Drag>,
+    Option<&'a Rft>,
+    Option<&'a Material>,
+    Option<&'a Muscle>,
+);
+
+#[allow(clippy::too_many_arguments)]
+fn save_checkpoint(
+    mut last: ResMut<LastCheckpoint>,
+    config: Res<Config>,
+    time: Res<TimeTracker>,
+    time_t: Res<TimeTracker2>,
+    time_int: Res<TimeTrackerInt>,
+    rng: Res<SimRng>,
+    worms: Query<WormQuery>,
+    nodes: Query<NodeQuery>,
+    springs: Query<SpringQuery>,
+    pressures: Query<(&Parent, &Pressure)>,
+    blobs: Query<(), With<Blob>>,
+    fitnesses: Query<&Fitnesses>,
+    lengths: Res<ControlLengths>
+) {
+    let (Some(path), Some(every)) = (&config.checkpoint, config.checkpoint_every) else { return };
+    if time.0 - last.0 < every { return }
+    last.0 = time.0;
+
+    let worm_ids: HashMap<Entity, u32> = worms.iter()
+        .enumerate()
+        .map(|(i, (entity, ..))| (entity, i as u32))
+        .collect();
+    let node_ids: HashMap<Entity, u32> = nodes.iter()
+        .filter(|(_, parent, ..)| worm_ids.contains_key(&parent.get()))
+        .enumerate()
+        .map(|(i, (entity, ..))| (entity, i as u32))
+        .collect();
+
+    let checkpoint = Checkpoint {
+        config: config.clone(),
+        time: time.0,
+        time_t: time_t.0,
+        time_int: time_int.0,
+        rng: rng.0.clone(),
+        worms: worms.iter()
+            .map(|(entity, id, initial, adder, development, preferred, worm, ctrnn, neurons, transform, cyclical, regional, frequency, manual, flux)| WormState {
+                id: id.0,
+                translation: transform.translation.to_array(),
+                initial: initial.0.map(|pos| pos.to_array()),
+                adder: (adder.segment, adder.neuron),
+                development: (development.brain, development.body),
+                preferred_temperature: preferred.0,
+                blob: blobs.contains(entity),
+                cyclical: cyclical.is_some(),
+                regional: regional.is_some(),
+                frequency: frequency.map(|f| (f.frequency, f.phase)),
+                manual: manual.is_some(),
+                update_flux: flux.is_some(),
+                neurons: neurons.0.clone(),
+                brain: BrainState {
+                    snapshot: BrainSnapshot::capture(ctrnn),
+                    output_history: ctrnn.output_history.clone(),
+                    flux_history: ctrnn.flux_history.clone(),
+                    activity_history: ctrnn.activity_history.clone(),
+                    fitness_history: ctrnn.fitness_history.clone(),
+                    fitness_sum: ctrnn.fitness_sum.clone(),
+                    avg_fitness_sum: ctrnn.avg_fitness_sum.clone(),
+                },
+                fitnesses: fitnesses.get(entity).map_or(vec![], |f| f.0.iter()
+                    .map(|fitness| (fitness.name().to_string(), fitness.state()))
+                    .collect()),
+                segments: worm.segments.iter()
+                    .map(|seg| (seg.index, seg.nodes.iter().map(|node| node_ids[node]).collect()))
+                    .collect(),
+            })
+            .collect(),
+        nodes: nodes.iter()
+            .filter_map(|(_, parent, pos, mass, drag, radius, index, head, contact, locked)| Some(NodeState {
+                worm: *worm_ids.get(&parent.get())?,
+                now: pos.now.to_array(),
+                last: pos.last.to_array(),
+                mass: mass.0,
+                drag: drag.map(|d| d.0),
+                radius: radius.map(|r| r.0),
+                index: index.map(|i| i.0),
+                head: head.is_some(),
+                contact: contact.map(|c| c.penetration),
+                locked: locked.is_some(),
+            }))
+            .collect(),
+        springs: springs.iter()
+            .filter_map(|(entity, parent, spring, control, hidden, drag, rft, material, muscle)| Some(SpringState {
+                worm: *worm_ids.get(&parent.get())?,
+                a: *node_ids.get(&spring.a)?,
+                b: *node_ids.get(&spring.b)?,
+                constant: spring.constant,
+                length: spring.length,
+                control: control.map(|c| (c.index, c.side)),
+                hidden: hidden.is_some(),
+                drag: drag.map(|d| d.0),
+                rft: rft.map(|r| (r.tangential, r.normal)),
+                material: material.copied(),
+                muscle: muscle.copied(),
+                last_length: lengths.0.get(&entity).copied(),
+            }))
+            .collect(),
+        pressures: pressures.iter()
+            .filter_map(|(parent, pressure)| Some(PressureState {
+                worm: *worm_ids.get(&parent.get())?,
+                nodes: pressure.nodes.iter().map(|node| node_ids.get(node).copied()).collect::<Option<_>>()?,
+                area: pressure.area,
+                stiffness: pressure.stiffness,
+            }))
+            .collect(),
+    };
+
+    if let Err(err) = checkpoint.save(path) {
+        eprintln!("{}", err);
+    }
+}
+
+/// Checkpoint a run continues from, as read by `Config::with_checkpoint`.
+#[derive(Resource)]
+pub struct Resume(pub Checkpoint);
+
+/// Startup system that rebuilds the world from `--resume` in place of `setup`.
+#[allow(clippy::too_many_arguments)]
+pub fn resume(
+    mut commands: Commands,
+    config: Res<Config>,
+    checkpoint: Option<Res<Resume>>,
+    mut time: ResMut<TimeTracker>,
+    mut time_t: ResMut<TimeTracker2>,
+    mut time_int: ResMut<TimeTrackerInt>,
+    mut rng: ResMut<SimRng>,
+    mut lengths: ResMut<ControlLengths>,
+    mut last: ResMut<LastCheckpoint>
+) {
+    let Some(checkpoint) = checkpoint else { return };
+    let checkpoint = &checkpoint.0;
+
+    time.0 = checkpoint.time;
+    last.0 = checkpoint.time;
+    time_t.0 = checkpoint.time_t;
+    time_int.0 = checkpoint.time_int;
+    rng.0 = checkpoint.rng.clone();
+
+    let parents: Vec<Entity> = checkpoint.worms.iter()
+        .map(|worm| {
+            let state = &worm.brain;
+            let mut brain = state.snapshot.restore();
+            brain.output_history = state.output_history.clone();
+            brain.flux_history = state.flux_history.clone();
+            brain.activity_history = state.activity_history.clone();
+            brain.fitness_history = state.fitness_history.clone();
+            brain.fitness_sum = state.fitness_sum.clone();
+            brain.avg_fitness_sum = state.avg_fitness_sum.clone();
+
+            let fitnesses = restore_fitnesses(&config, &worm.fitnesses)
+                .unwrap_or_else(|err| unreachable!("`Checkpoint::check` restores every fitness: {}", err));
+
+            let mut parent = commands.spawn((
+                Transform::default().with_translation(Vec3::from_array(worm.translation)),
+                GlobalTransform::default(),
+                VisibilityBundle::default(),
+                brain,
+                Inputs::default(),
+                Neurons(worm.neurons.clone()),
+                WormId(worm.id),
+                InitialPosition(worm.initial.map(Vec3::from_array)),
+                Adder { segment: worm.adder.0, neuron: worm.adder.1 },
+                Development { brain: worm.development.0, body: worm.development.1 },
+                PreferredTemperature(worm.preferred_temperature),
+                fitnesses
+            ));
+            if worm.blob { parent.insert(Blob); }
+            if worm.cyclical { parent.insert(CyclicalMapping); }
+            if worm.regional { parent.insert(RegionalMapping); }
+            if let Some((frequency, phase)) = worm.frequency {
+                parent.insert(FrequencyMapping { frequency, phase });
+            }
+            if worm.manual { parent.insert(ManualControl); }
+            if worm.update_flux { parent.insert(UpdateFlux); }
+            parent.id()
+        })
+        .collect();
+
+    let nodes: Vec<Entity> = checkpoint.nodes.iter()
+        .map(|node| {
+            let mut child = commands.spawn((
+                Position { now: Vec3::from_array(node.now), last: Vec3::from_array(node.last) },
+                Force::default(),
+                Mass(node.mass)
+            ));
+            if let Some(drag) = node.drag { child.insert(Drag(drag)); }
+            if let Some(radius) = node.radius { child.insert(Radius(radius)); }
+            if let Some(index) = node.index { child.insert(Index(index)); }
+            if node.head { child.insert(Head); }
+            if let Some(penetration) = node.contact { child.insert(ContactSensor { penetration }); }
+            if node.locked { child.insert(Locked); }
+            let id = child.id();
+            commands.entity(parents[node.worm as usize]).add_child(id);
+            id
+        })
+        .collect();
+
+    for spring in &checkpoint.springs {
+        let mut child = commands.spawn(Spring {
+            a: nodes[spring.a as usize],
+            b: nodes[spring.b as usize],
+            constant: spring.constant,
+            length: spring.length,
+        });
+        if let Some((index, side)) = spring.control { child.insert(Control { index, side }); }
+        if spring.hidden { child.insert(SpringHidden); }
+        if let Some(drag) = spring.drag { child.insert(Drag(drag)); }
+        if let Some((tangential, normal)) = spring.rft { child.insert(Rft { tangential, normal }); }
+        if let Some(material) = spring.material { child.insert(material); }
+        if let Some(muscle) = spring.muscle { child.insert(muscle); }
+        let id = child.id();
+        if let Some(length) = spring.last_length { lengths.0.insert(id, length); }
+        commands.entity(parents[spring.worm as usize]).add_child(id);
+    }
+
+    for pressure in &checkpoint.pressures {
+        let id = commands.spawn(Pressure {
+            nodes: pressure.nodes.iter().map(|node| nodes[*node as usize]).collect(),
+            area: pressure.area,
+            stiffness: pressure.stiffness,
+        }).id();
+        commands.entity(parents[pressure.worm as usize]).add_child(id);
+    }
+
+    for (worm, parent) in checkpoint.worms.iter().zip(&parents) {
+        let segments = worm.segments.iter()
+            .map(|(index, segment)| Segment {
+                index: *index,
+                nodes: segment.iter().map(|node| nodes[*node as usize]).collect(),
+            })
+            .collect();
+        commands.entity(*parent).insert(WormController::new(crate::default_controller, segments));
+    }
+    commands.remove_resource::<Resume>();
+}
+
+pub struct CheckpointPlugin;
+impl Plugin for CheckpointPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LastCheckpoint>();
+        app.add_system_to_stage(CoreStage::PostUpdate, save_checkpoint);
+        app.add_startup_system(resume);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(fitness: &str) -> Config {
+        Config::from_args(["--fitness".to_string(), fitness.to_string()]).unwrap()
+    }
+
+    #[test]
+    fn saved_fitnesses_restore_by_name() {
+        let mut fitnesses = Fitnesses::from_config(&config("displacement,straightness"));
+        for fitness in fitnesses.0.iter_mut() {
+            for x in [0.0, 3.0] {
+                fitness.update(&crate::fitness::Sample { time: x, com: Vec3::new(x, 4.0, 0.0), work: 1.0, isotherm: None });
+            }
+        }
+        let saved: Vec<(String, Vec<u8>)> = fitnesses.0.iter()
+            .map(|fitness| (fitness.name().to_string(), fitness.state()))
+            .collect();
+
+        let restored = restore_fitnesses(&config("displacement,straightness"), &saved).unwrap();
+        assert_eq!(restored.0.iter().map(|f| f.value()).collect::<Vec<_>>(), vec![3.0, 1.0]);
+        // A fitness selected in place of a saved one starts from scratch.
+        let swapped = restore_fitnesses(&config("mean_x,straightness"), &saved).unwrap();
+        assert_eq!(swapped.0.iter().map(|f| f.value()).collect::<Vec<_>>(), vec![0.0, 1.0]);
+    }
+
+    #[test]
+    fn corrupt_fitnesses_are_errors() {
+        let saved = vec![("displacement".to_string(), vec![1, 2])];
+        assert!(restore_fitnesses(&config("displacement"), &saved).is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use crate::{
+    config::Config,
+    physics::{Locked, Mass, Position, Radius},
+    scene::Obstacles,
+    sensors::ContactSensor,
+};
+
+/// Buckets points into square cells so only points in the same or a
+/// neighbouring cell have to be tested against each other.
+pub struct SpatialHash {
+    cell: f32,
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl SpatialHash {
+    /// `cell` should be at least the largest distance a pair can interact at.
+    pub fn new(cell: f32) -> Self {
+        Self { cell, cells: HashMap::new() }
+    }
+
+    fn key(&self, point: Vec3) -> (i32, i32) {
+        ((point.x / self.cell).floor() as i32, (point.y / self.cell).floor() as i32)
+    }
+
+    pub fn insert(&mut self, index: usize, point: Vec3) {
+        self.cells.entry(self.key(point)).or_default().push(index);
+    }
+
+    /// Every pair of indices in the same or adjacent cells, once each and
+    /// sorted, so resolving them is independent of hash map order.
+    pub fn pairs(&self) -> Vec<(usize, usize)> {
+        // Half of the neighbourhood, so each pair of cells is visited once.
+        const NEIGHBOURS: [(i32, i32); 4] = [(1, 0), (1, 1), (0, 1), (-1, 1)];
+        let mut pairs = vec![];
+        for (&(x, y), cell) in &self.cells {
+            for (i, a) in cell.iter().enumerate() {
+                for b in &cell[i + 1..] {
+                    pairs.push((*a.min(b), *a.max(b)));
+                }
+            }
+            for (dx, dy) in NEIGHBOURS {
+                let Some(other) = self.cells.get(&(x + dx, y + dy)) else { continue };
+                for a in cell {
+                    for b in other {
+                        pairs.push((*a.min(b), *a.max(b)));
+                    }
+                }
+            }
+        }
+        pairs.sort_unstable();
+        pairs
+    }
+}
+
+/// Pushes overlapping nodes apart along the line between them, split by
+/// inverse mass. Moving `now` without `last` also gives them the matching
+/// velocity on the next Verlet step.
+pub fn collide_nodes(
+    config: Res<Config>,
+    mut nodes: Query<(&mut Position, &Radius, &Mass, Option<&Locked>, Option<&mut ContactSensor>)>
+) {
+    if !config.collisions { return }
+    let mut nodes: Vec<_> = nodes.iter_mut().collect();
+    let largest = nodes.iter().map(|(_, radius, ..)| radius.0).fold(0.0, f32::max);
+    if largest <= 0.0 { return }
+
+    let mut hash = SpatialHash::new(2.0 * largest);
+    for (i, (pos, ..)) in nodes.iter().enumerate() {
+        hash.insert(i, pos.now);
+    }
+
+    type Node<'a> = (Mut<'a, Position>, &'a Radius, &'a Mass, Option<&'a Locked>, Option<Mut<'a, ContactSensor>>);
+    let inverse_mass = |(_, _, mass, locked, _): &Node| {
+        if locked.is_some() || mass.0 <= 0.0 { 0.0 } else { 1.0 / mass.0 }
+    };
+    for (i, j) in hash.pairs() {
+        let diff = nodes[j].0.now - nodes[i].0.now;
+        let dist = diff.length();
+        let min = nodes[i].1.0 + nodes[j].1.0;
+        if dist >= min || dist == 0.0 { continue }
+
+        let (w_i, w_j) = (inverse_mass(&nodes[i]), inverse_mass(&nodes[j]));
+        if w_i + w_j == 0.0 { continue }
+        let correction = diff / dist * (min - dist) / (w_i + w_j);
+        nodes[i].0.now -= correction * w_i;
+        nodes[j].0.now += correction * w_j;
+        for k in [i, j] {
+            if let Some(sensor) = &mut nodes[k].4 { sensor.touch(min - dist); }
+        }
+    }
+}
+
+/// Moves nodes out of every obstacle they overlap. Runs after node collisions
+/// so obstacles win when the two disagree.
+pub fn collide_obstacles(
+    obstacles: Res<Obstacles>,
+    mut nodes: Query<(&mut Position, &Radius, Option<&mut ContactSensor>), Without<Locked>>
+) {
+    if obstacles.0.is_empty() { return }
+    for (mut pos, radius, mut sensor) in nodes.iter_mut() {
+        for obstacle in &obstacles.0 {
+            if let Some(push) = obstacle.push_out(pos.now.truncate(), radius.0) {
+                pos.now += push.extend(0.0);
+                if let Some(sensor) = &mut sensor { sensor.touch(push.length()); }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[test]
+    fn pairs_cover_every_close_pair_once() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let points: Vec<Vec3> = (0..200)
+            .map(|_| Vec3::new(rng.gen_range(-5.0..5.0), rng.gen_range(-5.0..5.0), 0.0))
+            .collect();
+        let mut hash = SpatialHash::new(1.0);
+        for (i, point) in points.iter().enumerate() {
+            hash.insert(i, *point);
+        }
+        let pairs = hash.pairs();
+        let unique: HashSet<_> = pairs.iter().copied().collect();
+        assert_eq!(unique.len(), pairs.len());
+        assert!(pairs.iter().all(|(a, b)| a < b));
+        for i in 0..points.len() {
+            for j in i + 1..points.len() {
+                if points[i].distance(points[j]) < 1.0 {
+                    assert!(unique.contains(&(i, j)), "{:?} and {:?} not paired", points[i], points[j]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pairs_reach_diagonal_neighbours() {
+        let mut hash = SpatialHash::new(1.0);
+        hash.insert(0, Vec3::new(0.9, 0.1, 0.0));
+        hash.insert(1, Vec3::new(1.1, -0.1, 0.0));
+        hash.insert(2, Vec3::new(-0.1, 1.1, 0.0));
+        hash.insert(3, Vec3::new(3.5, 3.5, 0.0));
+        assert_eq!(hash.pairs(), vec![(0, 1), (0, 2)]);
+    }
+}
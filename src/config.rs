@@ -0,0 +1,504 @@
+use std::{fs, path::{Path, PathBuf}, str::FromStr};
+
+use bevy::prelude::Resource;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blob::BlobShape,
+    body::BodyPlan,
+    brain::{BrainSnapshot, CTRNN},
+    checkpoint::Checkpoint,
+    field::ScalarField,
+    fitness::FitnessKind,
+    integrator::Integrator,
+    medium::{Flow, Medium, MediumSettings},
+    muscle::MuscleSettings,
+    recording::Channel,
+    scene::Scene,
+    sensors::{Chemotaxis, Proprioception, Thermotaxis, Touch},
+    step::Timestep,
+    worm::SpringClasses,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mapping {
+    Cyclical,
+    Regional,
+}
+
+impl FromStr for Mapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cyclical" => Ok(Self::Cyclical),
+            "regional" => Ok(Self::Regional),
+            _ => Err(format!("unknown mapping `{}`", s)),
+        }
+    }
+}
+
+/// Which resistive forces act on the worm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Friction {
+    /// Normal drag on `Drag` springs plus quadratic drag on every node.
+    Drag,
+    /// Resistive force theory on `Rft` springs.
+    Rft,
+}
+
+impl FromStr for Friction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drag" => Ok(Self::Drag),
+            "rft" => Ok(Self::Rft),
+            _ => Err(format!("unknown friction `{}`", s)),
+        }
+    }
+}
+
+/// Resistive force theory coefficients of the medium, force per unit of
+/// spring length per unit of speed along and across the spring.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RftCoefficients {
+    pub tangential: f32,
+    pub normal: f32,
+}
+
+impl RftCoefficients {
+    /// Crawling on agar, where moving sideways is much harder than along the
+    /// body.
+    pub const AGAR: Self = Self { tangential: 1.0, normal: 20.0 };
+    /// Swimming, with a normal to tangential ratio of about 1.5.
+    pub const WATER: Self = Self { tangential: 1.0, normal: 1.5 };
+}
+
+impl Default for RftCoefficients {
+    fn default() -> Self { Self::AGAR }
+}
+
+impl FromStr for RftCoefficients {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "agar" => Ok(Self::AGAR),
+            "water" => Ok(Self::WATER),
+            _ => Err(format!("unknown medium `{}`", s)),
+        }
+    }
+}
+
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WormSettings {
+    pub frequency: f32,
+    pub phase: f32,
+    pub neurons: usize,
+    pub segments: usize,
+    pub adult_segments: usize,
+    pub springs: SpringClasses,
+    /// Layout of the body's nodes and springs.
+    pub body: BodyPlan,
+    /// Hill-type muscles on the control springs.
+    pub muscle: MuscleSettings,
+}
+
+impl Default for WormSettings {
+    fn default() -> Self {
+        Self {
+            frequency: 0.1,
+            phase: 2.0 * std::f32::consts::PI / 6.0,
+            neurons: 6,
+            segments: 2,
+            adult_segments: 12,
+            springs: SpringClasses::default(),
+            body: BodyPlan::default(),
+            muscle: MuscleSettings::default(),
+        }
+    }
+}
+
+/// One worm, or blob, in the world. Unset fields fall back to the top-level
+/// settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WormSpec {
+    /// Where the head is spawned.
+    pub position: [f32; 2],
+    pub mapping: Option<Mapping>,
+    pub devo_brain: Option<bool>,
+    pub devo_body: Option<bool>,
+    /// Segments the worm is spawned with, in place of `segments` or
+    /// `adult_segments`.
+    pub segments: Option<usize>,
+    pub brain: Option<PathBuf>,
+    /// Temperature the worm seeks, in place of `Thermotaxis::preferred`.
+    pub preferred_temperature: Option<f32>,
+    /// Spawns this blob instead of a worm.
+    pub blob: Option<BlobShape>,
+}
+
+/// Everything that used to be a compile-time switch in `main.rs`, loaded from
+/// a TOML experiment file and then patched by named command line overrides.
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub gui: bool,
+    pub devo_brain: bool,
+    pub devo_body: bool,
+    pub mapping: Mapping,
+    pub log_every_frame: bool,
+    pub history_length: usize,
+    pub run_length: f32,
+    /// Seed for every random draw in the run; a fresh one is picked and
+    /// recorded when left unset.
+    pub seed: Option<u64>,
+    /// Directory that recorded channels are written to, stdout when unset.
+    pub output_dir: Option<PathBuf>,
+    pub channels: Vec<Channel>,
+    /// Samples per second of experiment time for every recorded channel.
+    pub record_rate: f32,
+    /// File every tick is recorded to for `blob replay`.
+    pub replay: Option<PathBuf>,
+    /// Saved brain every worm starts from instead of the hand-tuned one.
+    pub brain: Option<PathBuf>,
+    /// Where the first worm's brain is saved when the run exits.
+    pub save_brain: Option<PathBuf>,
+    /// File the simulation state is periodically written to.
+    pub checkpoint: Option<PathBuf>,
+    /// Seconds of experiment time between checkpoints.
+    pub checkpoint_every: Option<f32>,
+    /// Checkpoint the run continues from.
+    pub resume: Option<PathBuf>,
+    /// Fitness functions reported at the end of the run; the first one is
+    /// what sweeps and evolution score by.
+    pub fitness: Vec<FitnessKind>,
+    /// Direction the `speed` fitness measures along.
+    pub direction: [f32; 2],
+    /// Point the `target` fitness measures approach to.
+    pub target: [f32; 2],
+    pub timestep: Timestep,
+    pub integrator: Integrator,
+    /// Whether nodes collide with each other.
+    pub collisions: bool,
+    pub friction: Friction,
+    /// Medium the `rft` friction model resists motion with.
+    pub rft: RftCoefficients,
+    pub medium: MediumSettings,
+    /// Scene file holding the obstacles worms collide with.
+    pub scene: Option<PathBuf>,
+    pub worm: WormSettings,
+    /// Stretch receptors feeding the brain.
+    pub proprioception: Proprioception,
+    /// Chemical field smelled at the head.
+    pub chemotaxis: Chemotaxis,
+    /// Temperature field felt at the head.
+    pub thermotaxis: Thermotaxis,
+    /// Contact sensors on the head and side nodes.
+    pub touch: Touch,
+    /// Worms sharing the world, a single default worm when empty.
+    pub worms: Vec<WormSpec>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            gui: true,
+            devo_brain: false,
+            devo_body: false,
+            mapping: Mapping::Cyclical,
+            log_every_frame: true,
+            history_length: 500,
+            run_length: 600.0,
+            seed: None,
+            output_dir: None,
+            channels: vec![Channel::Com],
+            record_rate: 10.0,
+            replay: None,
+            brain: None,
+            save_brain: None,
+            checkpoint: None,
+            checkpoint_every: None,
+            resume: None,
+            fitness: vec![FitnessKind::MeanX],
+            direction: [1.0, 0.0],
+            target: [10.0, 0.0],
+            timestep: Timestep::default(),
+            integrator: Integrator::Verlet,
+            collisions: true,
+            friction: Friction::Drag,
+            rft: RftCoefficients::default(),
+            medium: MediumSettings::default(),
+            scene: None,
+            worm: WormSettings::default(),
+            proprioception: Proprioception::default(),
+            chemotaxis: Chemotaxis::default(),
+            thermotaxis: Thermotaxis::default(),
+            touch: Touch::default(),
+            worms: vec![],
+        }
+    }
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value `{}` for {}", value, flag))
+}
+
+fn parse_pair(flag: &str, value: &str) -> Result<[f32; 2], String> {
+    match value.split_once(',') {
+        Some((x, y)) => Ok([parse(flag, x)?, parse(flag, y)?]),
+        None => Err(format!("{} expects x,y", flag)),
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("could not parse {}: {}", path.display(), e))
+    }
+
+    /// Builds a config from `--config <file>` or the config stored in
+    /// `--resume <checkpoint>` (if given) and then applies every other flag on
+    /// top of it, in order. Only single runs can resume, see `with_checkpoint`.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        match Self::with_checkpoint(args)? {
+            (_, Some(_)) => Err("--resume only applies to a single run".to_string()),
+            (config, None) => Ok(config),
+        }
+    }
+
+    /// Like `from_args`, also handing back the checkpoint the run resumes
+    /// from so it is only read once.
+    pub fn with_checkpoint(
+        args: impl IntoIterator<Item = String>
+    ) -> Result<(Self, Option<Checkpoint>), String> {
+        let args: Vec<String> = args.into_iter().collect();
+        let path_after = |flag: &str| match args.iter().position(|arg| arg == flag) {
+            Some(i) => args.get(i + 1).map(Some).ok_or(format!("{} expects a path", flag)),
+            None => Ok(None),
+        };
+        let (mut config, mut checkpoint) = match (path_after("--config")?, path_after("--resume")?) {
+            (Some(_), Some(_)) => return Err("--config and --resume cannot be combined".to_string()),
+            (Some(path), None) => (Self::load(path)?, None),
+            (None, Some(path)) => {
+                let checkpoint = Checkpoint::load(path)?;
+                let mut config = checkpoint.config.clone();
+                config.resume = Some(PathBuf::from(path));
+                (config, Some(checkpoint))
+            }
+            (None, None) => (Self::default(), None),
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} expects a value", flag));
+            match flag.as_str() {
+                "--config" | "--resume" => { value()?; }
+                "--gui" => config.gui = parse(flag, value()?)?,
+                "--nogui" => config.gui = false,
+                "--devo-brain" => config.devo_brain = parse(flag, value()?)?,
+                "--devo-body" => config.devo_body = parse(flag, value()?)?,
+                "--mapping" => config.mapping = value()?.parse()?,
+                "--log-every-frame" => config.log_every_frame = parse(flag, value()?)?,
+                "--history-length" => config.history_length = parse(flag, value()?)?,
+                "--run-length" => config.run_length = parse(flag, value()?)?,
+                "--seed" => config.seed = Some(parse(flag, value()?)?),
+                "--output-dir" => config.output_dir = Some(PathBuf::from(value()?)),
+                "--channels" => config.channels = value()?.split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+                "--record-rate" => config.record_rate = parse(flag, value()?)?,
+                "--record-replay" => config.replay = Some(PathBuf::from(value()?)),
+                "--brain" => config.brain = Some(PathBuf::from(value()?)),
+                "--save-brain" => config.save_brain = Some(PathBuf::from(value()?)),
+                "--checkpoint" => config.checkpoint = Some(PathBuf::from(value()?)),
+                "--checkpoint-every" => config.checkpoint_every = Some(parse(flag, value()?)?),
+                "--fitness" => config.fitness = value()?.split(',')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()?,
+                "--direction" => config.direction = parse_pair(flag, value()?)?,
+                "--target" => config.target = parse_pair(flag, value()?)?,
+                "--substeps" => config.timestep.substeps = parse(flag, value()?)?,
+                "--physics-dt" => config.timestep.physics_dt = parse(flag, value()?)?,
+                "--integrator" => config.integrator = value()?.parse()?,
+                "--collisions" => config.collisions = parse(flag, value()?)?,
+                "--scene" => config.scene = Some(PathBuf::from(value()?)),
+                "--friction" => config.friction = value()?.parse()?,
+                "--rft-medium" => config.rft = value()?.parse()?,
+                "--rft-tangential" => config.rft.tangential = parse(flag, value()?)?,
+                "--rft-normal" => config.rft.normal = parse(flag, value()?)?,
+                "--density" => config.medium.density = parse(flag, value()?)?,
+                "--viscosity" => config.medium.viscosity = parse(flag, value()?)?,
+                "--flow" => config.medium.flow = Flow::Uniform { velocity: parse_pair(flag, value()?)? },
+                "--frequency" => config.worm.frequency = parse(flag, value()?)?,
+                "--phase" => config.worm.phase = parse(flag, value()?)?,
+                "--neurons" => config.worm.neurons = parse(flag, value()?)?,
+                "--segments" => config.worm.segments = parse(flag, value()?)?,
+                "--adult-segments" => config.worm.adult_segments = parse(flag, value()?)?,
+                "--muscles" => config.worm.muscle.enabled = parse(flag, value()?)?,
+                "--max-force" => config.worm.muscle.max_force = parse(flag, value()?)?,
+                "--pressure" => config.worm.body.pressure = parse(flag, value()?)?,
+                "--stretch-gain" => config.proprioception.gain = parse(flag, value()?)?,
+                "--preferred-temperature" => config.thermotaxis.preferred = parse(flag, value()?)?,
+                "--touch-threshold" => config.touch.threshold = parse(flag, value()?)?,
+                _ => return Err(format!("unknown argument `{}`", flag)),
+            }
+        }
+
+        if let (Some(path), None) = (&config.resume, &checkpoint) {
+            checkpoint = Some(Checkpoint::load(path)?);
+        }
+        config.check()?;
+        if let Some(checkpoint) = &checkpoint { checkpoint.check(&config)?; }
+        Ok((config, checkpoint))
+    }
+
+    /// Settings that parse but cannot run, caught before anything is built.
+    pub fn check(&self) -> Result<(), String> {
+        self.worm.body.check()?;
+        if self.fitness.contains(&FitnessKind::Chemotaxis) && self.chemotaxis.field.is_none() {
+            return Err("the chemotaxis fitness needs a [chemotaxis] field".to_string());
+        }
+        for field in [&self.chemotaxis.field, &self.thermotaxis.field].into_iter().flatten() {
+            ScalarField::new(field)?;
+        }
+        Medium::new(&self.medium)?;
+        if let Some(path) = &self.scene {
+            Scene::load(path)?;
+        }
+        let mut neurons = usize::MAX;
+        for spec in self.worms() {
+            if let Some(shape) = &spec.blob { shape.check()?; }
+            let devo_brain = spec.devo_brain.unwrap_or(self.devo_brain);
+            let count = match spec.brain.as_ref().or(self.brain.as_ref()) {
+                Some(path) => BrainSnapshot::load(path)?.count,
+                None => CTRNN::trained_ctrnn(devo_brain).count,
+            };
+            let grown = if devo_brain { (self.run_length / crate::GROWTH_INTERVAL as f32) as usize } else { 0 };
+            neurons = neurons.min(count + grown);
+        }
+        let receptors = self.proprioception.receptors.iter().map(|receptor| ("stretch receptor", receptor.neuron))
+            .chain(self.touch.receptors.iter().map(|receptor| ("touch receptor", receptor.neuron)))
+            .chain(self.chemotaxis.field.as_ref().map(|_| ("chemotaxis", self.chemotaxis.neuron)))
+            .chain(self.thermotaxis.field.as_ref().map(|_| ("thermotaxis", self.thermotaxis.neuron)));
+        for (name, neuron) in receptors {
+            if neuron >= neurons {
+                return Err(format!("{} neuron {} is past the {} neurons every brain has", name, neuron, neurons));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every worm to spawn, in `WormId` order.
+    pub fn worms(&self) -> Vec<WormSpec> {
+        if self.worms.is_empty() { vec![WormSpec::default()] } else { self.worms.clone() }
+    }
+
+    /// The run's seed, drawing and remembering one if none was given.
+    pub fn seed(&mut self) -> u64 {
+        *self.seed.get_or_insert_with(rand::random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let config = Config::from_args(args("--nogui --mapping regional --run-length 30 --seed 7")).unwrap();
+        assert!(!config.gui);
+        assert_eq!(config.mapping, Mapping::Regional);
+        assert_eq!(config.run_length, 30.0);
+        assert_eq!(config.seed, Some(7));
+        assert_eq!(config.history_length, Config::default().history_length);
+    }
+
+    #[test]
+    fn booleans_take_a_value() {
+        let config = Config::from_args(args("--gui false --devo-brain true --devo-body true")).unwrap();
+        assert!(!config.gui && config.devo_brain && config.devo_body);
+        let config = Config::from_args(args("--devo-brain true --devo-brain false --devo-body false")).unwrap();
+        assert!(!config.devo_brain && !config.devo_body);
+        assert!(Config::from_args(args("--devo-body")).is_err());
+        assert!(Config::from_args(args("--devo-brain yes")).is_err());
+    }
+
+    #[test]
+    fn flags_apply_on_top_of_the_file() {
+        let path = std::env::temp_dir().join(format!("blob-config-{}.toml", std::process::id()));
+        fs::write(&path, "run_length = 10.0\nhistory_length = 20\n[worm]\nsegments = 3\n").unwrap();
+        let line = format!("--run-length 5 --config {} --segments 4", path.display());
+        let config = Config::from_args(args(&line));
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.run_length, 5.0);
+        assert_eq!(config.history_length, 20);
+        assert_eq!(config.worm.segments, 4);
+    }
+
+    #[test]
+    fn pairs_and_lists_parse() {
+        let config = Config::from_args(args("--direction 0,-1 --fitness speed,target")).unwrap();
+        assert_eq!(config.direction, [0.0, -1.0]);
+        assert_eq!(config.fitness, vec![FitnessKind::Speed, FitnessKind::Target]);
+    }
+
+    #[test]
+    fn bad_flags_are_errors() {
+        assert!(Config::from_args(args("--bogus")).is_err());
+        assert!(Config::from_args(args("--run-length")).is_err());
+        assert!(Config::from_args(args("--run-length soon")).is_err());
+        assert!(Config::from_args(args("--direction 1")).is_err());
+        assert!(Config::from_args(args("--mapping diagonal")).is_err());
+        assert!(Config::from_args(args("--config")).is_err());
+    }
+
+    #[test]
+    fn brains_are_loaded_when_checked() {
+        assert!(Config::from_args(args("--brain /nonexistent/brain.toml")).is_err());
+        let path = std::env::temp_dir().join(format!("blob-brain-{}.toml", std::process::id()));
+        fs::write(&path, "count = 2\nvoltages = [0.0]\nbiases = []\nweights = []\n").unwrap();
+        let config = Config::from_args(args(&format!("--brain {}", path.display())));
+        fs::remove_file(&path).unwrap();
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn scenes_are_loaded_when_checked() {
+        assert!(Config::from_args(args("--scene /nonexistent/scene.toml")).is_err());
+    }
+
+    #[test]
+    fn receptors_must_name_a_neuron_every_brain_has() {
+        let path = std::env::temp_dir().join(format!("blob-receptors-{}.toml", std::process::id()));
+        let receptor = |neuron: usize| format!(
+            "[proprioception]\nreceptors = [{{ segment = 1, side = \"left\", neuron = {} }}]\n", neuron
+        );
+        let check = |text: String, line: &str| {
+            fs::write(&path, text).unwrap();
+            Config::from_args(args(&format!("--config {} {}", path.display(), line)))
+        };
+        let adult = check(receptor(9), "--devo-brain false");
+        let past_adult = check(receptor(10), "--devo-brain false");
+        let unborn = check(receptor(3), "--devo-brain true --run-length 90");
+        let grown = check(receptor(3), "--devo-brain true --run-length 130");
+        fs::remove_file(&path).unwrap();
+        assert!(adult.is_ok());
+        assert!(past_adult.is_err());
+        assert!(unborn.is_err());
+        assert!(grown.is_ok());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{fs::File, io::Write};
+
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal};
+
+use crate::{
+    brain::{BrainSnapshot, CTRNN},
+    config::Config,
+    sweep::{default_threads, evaluate, evaluate_all},
+};
+
+/// Bias and weight centers are kept inside this range after mutation.
+const GENE_RANGE: f64 = 16.0;
+
+fn genes(brain: &mut BrainSnapshot) -> impl Iterator<Item = &mut f64> {
+    brain.biases.iter_mut()
+        .chain(brain.weights.iter_mut())
+        .map(|flux| &mut flux.center)
+}
+
+fn mutate(brain: &mut BrainSnapshot, sigma: f64, rng: &mut impl Rng) {
+    let normal = Normal::new(0.0, sigma).unwrap();
+    for gene in genes(brain) {
+        *gene = (*gene + normal.sample(rng)).clamp(-GENE_RANGE, GENE_RANGE);
+    }
+}
+
+/// Uniform crossover: every gene comes from either parent with equal odds.
+fn crossover(a: &BrainSnapshot, b: &BrainSnapshot, rng: &mut impl Rng) -> BrainSnapshot {
+    let mut child = a.clone();
+    let mut other = b.clone();
+    for (gene, theirs) in genes(&mut child).zip(genes(&mut other)) {
+        if rng.gen_bool(0.5) { *gene = *theirs; }
+    }
+    child
+}
+
+fn tournament<'a>(
+    population: &'a [BrainSnapshot],
+    fitness: &[f32],
+    size: usize,
+    rng: &mut impl Rng
+) -> &'a BrainSnapshot {
+    let indices: Vec<usize> = (0..population.len()).collect();
+    let best = indices.choose_multiple(rng, size.max(1))
+        .max_by(|a, b| fitness[**a].total_cmp(&fitness[**b]))
+        .unwrap();
+    &population[*best]
+}
+
+/// `blob evolve [--generations n] [--population n] [--elite n] [--sigma s]
+/// [--tournament n] [--threads n] [--out best.toml] [--stats stats.csv] [config flags]`
+///
+/// Genetic search over the bias and weight centers of the starting brain
+/// (`--brain`, or the hand-tuned network), scoring each genome by the
+/// headless run's outcome.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut generations = 50;
+    let mut size = 32;
+    let mut elite = 2;
+    let mut sigma = 0.5;
+    let mut tournament_size = 3;
+    let mut threads = default_threads();
+    let mut out = "best.toml".to_string();
+    let mut stats = None;
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", flag));
+        let invalid = || format!("invalid value for {}", flag);
+        match flag.as_str() {
+            "--generations" => generations = value()?.parse().map_err(|_| invalid())?,
+            "--population" => size = value()?.parse().map_err(|_| invalid())?,
+            "--elite" => elite = value()?.parse().map_err(|_| invalid())?,
+            "--sigma" => sigma = value()?.parse().map_err(|_| invalid())?,
+            "--tournament" => tournament_size = value()?.parse().map_err(|_| invalid())?,
+            "--threads" => threads = value()?.parse().map_err(|_| invalid())?,
+            "--out" => out = value()?.clone(),
+            "--stats" => stats = Some(value()?.clone()),
+            _ => rest.push(flag.clone()),
+        }
+    }
+    if size == 0 || elite > size {
+        return Err("--population must be positive and at least --elite".to_string());
+    }
+
+    let mut config = Config::from_args(rest)?;
+    config.gui = false;
+    let seed = config.seed();
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+    let base = match &config.brain {
+        Some(path) => BrainSnapshot::load(path)?,
+        None => BrainSnapshot::capture(&CTRNN::trained(config.devo_brain, &mut rng)),
+    };
+
+    let mut stats = match &stats {
+        Some(path) => {
+            let mut file = File::create(path).map_err(|e| format!("could not create {}: {}", path, e))?;
+            writeln!(file, "# seed: {}", seed).map_err(|e| e.to_string())?;
+            writeln!(file, "generation,best,mean,worst").map_err(|e| e.to_string())?;
+            Some(file)
+        }
+        None => None,
+    };
+
+    let mut population: Vec<BrainSnapshot> = (0..size)
+        .map(|i| {
+            let mut brain = base.clone();
+            if i > 0 { mutate(&mut brain, sigma, &mut rng); }
+            brain
+        })
+        .collect();
+    let mut best = f32::NEG_INFINITY;
+
+    for generation in 0..generations {
+        let fitness: Vec<f32> = evaluate_all(&population, threads, |brain| {
+            evaluate(config.clone(), Some(brain.clone())).first().copied().unwrap_or(f32::NAN)
+        })
+            .into_iter()
+            .map(|f| if f.is_nan() { f32::NEG_INFINITY } else { f })
+            .collect();
+
+        let mut ranked: Vec<usize> = (0..population.len()).collect();
+        ranked.sort_by(|a, b| fitness[*b].total_cmp(&fitness[*a]));
+        let mean = fitness.iter().sum::<f32>() / fitness.len() as f32;
+        let (top, worst) = (fitness[ranked[0]], fitness[ranked[ranked.len() - 1]]);
+        eprintln!("generation {}: best {} mean {} worst {}", generation, top, mean, worst);
+        if let Some(file) = &mut stats {
+            writeln!(file, "{},{},{},{}", generation, top, mean, worst).map_err(|e| e.to_string())?;
+        }
+        if top > best {
+            best = top;
+            population[ranked[0]].save(&out)?;
+        }
+
+        let mut next: Vec<BrainSnapshot> = ranked.iter()
+            .take(elite)
+            .map(|i| population[*i].clone())
+            .collect();
+        while next.len() < size {
+            let a = tournament(&population, &fitness, tournament_size, &mut rng);
+            let b = tournament(&population, &fitness, tournament_size, &mut rng);
+            let mut child = crossover(a, b, &mut rng);
+            mutate(&mut child, sigma, &mut rng);
+            next.push(child);
+        }
+        population = next;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brain::FluxSnapshot;
+
+    use super::*;
+
+    fn brain(center: f64) -> BrainSnapshot {
+        let flux = FluxSnapshot { center, period: (6.0, 12.0) };
+        BrainSnapshot { count: 3, voltages: vec![0.0; 3], biases: vec![flux.clone(); 3], weights: vec![flux; 9] }
+    }
+
+    fn centers(mut brain: BrainSnapshot) -> Vec<f64> {
+        genes(&mut brain).map(|gene| *gene).collect()
+    }
+
+    #[test]
+    fn mutation_is_seeded_and_bounded() {
+        let mutated = |seed| {
+            let mut child = brain(GENE_RANGE - 0.1);
+            mutate(&mut child, 1.0, &mut ChaCha8Rng::seed_from_u64(seed));
+            centers(child)
+        };
+        assert_eq!(mutated(1), mutated(1));
+        assert_ne!(mutated(1), mutated(2));
+        assert!(mutated(1).iter().all(|gene| gene.abs() <= GENE_RANGE));
+        assert!(mutated(1).iter().any(|gene| *gene != GENE_RANGE - 0.1));
+    }
+
+    #[test]
+    fn crossover_takes_every_gene_from_a_parent() {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let child = centers(crossover(&brain(0.0), &brain(1.0), &mut rng));
+        assert_eq!(child.len(), 12);
+        assert!(child.iter().all(|gene| *gene == 0.0 || *gene == 1.0));
+        assert!(child.contains(&0.0) && child.contains(&1.0));
+    }
+
+    #[test]
+    fn tournaments_pick_the_fittest_entrant() {
+        let population: Vec<BrainSnapshot> = (0..4).map(|i| brain(i as f64)).collect();
+        let fitness = [0.5, 3.0, 1.0, 2.0];
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let winner = tournament(&population, &fitness, 4, &mut rng);
+        assert_eq!(winner.biases[0].center, 1.0);
+        for _ in 0..20 {
+            let entrant = tournament(&population, &fitness, 1, &mut rng);
+            assert!(population.iter().any(|brain| brain.biases[0].center == entrant.biases[0].center));
+        }
+    }
+}
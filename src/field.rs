@@ -0,0 +1,202 @@
+use std::{
+    ops::{Add, Mul, Sub},
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Gaussian bump, the profile of a substance released at a point and left to
+/// diffuse: `spread` is `sqrt(2 * diffusivity * time)`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    pub position: [f32; 2],
+    pub strength: f32,
+    pub spread: f32,
+}
+
+impl Source {
+    fn at(&self, point: Vec2) -> f32 {
+        let r_sq = point.distance_squared(Vec2::from_array(self.position));
+        self.strength * (-r_sq / (2.0 * self.spread * self.spread)).exp()
+    }
+}
+
+/// A scalar quantity over the plane, such as a chemical's concentration.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Field {
+    /// Sum of point sources.
+    Sources { sources: Vec<Source> },
+    /// `value` at `origin`, changing by `gradient` per unit.
+    Linear { origin: [f32; 2], value: f32, gradient: [f32; 2] },
+    /// `value` at `center`, changing by `slope` per unit away from it.
+    Radial { center: [f32; 2], value: f32, slope: f32 },
+    /// Grayscale image scaled to `[0, scale]`, its bottom left pixel centered
+    /// on `origin` and `cell` units per pixel. Clamped to the edge outside.
+    Image { path: PathBuf, origin: [f32; 2], cell: f32, scale: f32 },
+}
+
+/// Values on a regular grid, stored row by row from `origin` upwards,
+/// bilinearly interpolated in between and clamped to the edge outside.
+#[derive(Debug, Clone)]
+pub struct Grid<T> {
+    origin: Vec2,
+    cell: f32,
+    width: usize,
+    height: usize,
+    values: Vec<T>,
+}
+
+impl<T> Grid<T>
+where
+    T: Copy + Add<Output = T> + Sub<Output = T> + Mul<f32, Output = T>,
+{
+    /// `values` must hold `width * height` entries, both at least 1.
+    pub fn new(origin: Vec2, cell: f32, width: usize, height: usize, values: Vec<T>) -> Self {
+        Self { origin, cell, width, height, values }
+    }
+
+    fn at(&self, x: usize, y: usize) -> T {
+        self.values[y * self.width + x]
+    }
+
+    pub fn sample(&self, point: Vec2) -> T {
+        let local = (point - self.origin) / self.cell;
+        let max = Vec2::new((self.width - 1) as f32, (self.height - 1) as f32);
+        let local = local.clamp(Vec2::ZERO, max);
+        let (x0, y0) = (local.x.floor() as usize, local.y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (tx, ty) = (local.x.fract(), local.y.fract());
+        let lerp = |a: T, b: T, t: f32| a + (b - a) * t;
+        let bottom = lerp(self.at(x0, y0), self.at(x1, y0), tx);
+        let top = lerp(self.at(x0, y1), self.at(x1, y1), tx);
+        lerp(bottom, top, ty)
+    }
+}
+
+impl Grid<f32> {
+    fn load(path: &Path, origin: [f32; 2], cell: f32, scale: f32) -> Result<Self, String> {
+        let image = image::open(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?
+            .into_luma8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        if width == 0 || height == 0 {
+            return Err(format!("{} is empty", path.display()));
+        }
+        // Image rows run top to bottom, the grid's bottom to top.
+        let values = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, height - 1 - y)))
+            .map(|(x, y)| image.get_pixel(x as u32, y as u32).0[0] as f32 / 255.0 * scale)
+            .collect();
+        Ok(Self::new(Vec2::from_array(origin), cell, width, height, values))
+    }
+
+    fn peak(&self) -> Vec2 {
+        let i = (0..self.values.len())
+            .max_by(|a, b| self.values[*a].total_cmp(&self.values[*b]))
+            .unwrap_or(0);
+        self.origin + Vec2::new((i % self.width) as f32, (i / self.width) as f32) * self.cell
+    }
+}
+
+/// A `Field` ready to sample, with any image already loaded.
+#[derive(Debug, Clone)]
+pub struct ScalarField {
+    field: Field,
+    grid: Option<Grid<f32>>,
+}
+
+impl ScalarField {
+    pub fn new(field: &Field) -> Result<Self, String> {
+        let grid = match field {
+            Field::Image { path, origin, cell, scale } => Some(Grid::load(path, *origin, *cell, *scale)?),
+            _ => None,
+        };
+        Ok(Self { field: field.clone(), grid })
+    }
+
+    pub fn sample(&self, point: Vec3) -> f32 {
+        let point = point.truncate();
+        match &self.field {
+            Field::Sources { sources } => sources.iter().map(|source| source.at(point)).sum(),
+            Field::Linear { origin, value, gradient } => {
+                value + (point - Vec2::from_array(*origin)).dot(Vec2::from_array(*gradient))
+            }
+            Field::Radial { center, value, slope } => {
+                value + point.distance(Vec2::from_array(*center)) * slope
+            }
+            Field::Image { .. } => self.grid.as_ref().map_or(0.0, |grid| grid.sample(point)),
+        }
+    }
+
+    /// Where the field is highest: the strongest source or brightest pixel.
+    /// Gradients have no peak and give their origin or center.
+    pub fn peak(&self) -> Vec2 {
+        match &self.field {
+            Field::Sources { sources } => sources.iter()
+                .map(|source| Vec2::from_array(source.position))
+                .max_by(|a, b| self.sample(a.extend(0.0)).total_cmp(&self.sample(b.extend(0.0))))
+                .unwrap_or(Vec2::ZERO),
+            Field::Linear { origin, .. } => Vec2::from_array(*origin),
+            Field::Radial { center, .. } => Vec2::from_array(*center),
+            Field::Image { .. } => self.grid.as_ref().map_or(Vec2::ZERO, Grid::peak),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grids_interpolate_between_cells() {
+        let grid = Grid { origin: Vec2::ONE, cell: 2.0, width: 2, height: 2, values: vec![0.0, 1.0, 2.0, 3.0] };
+        assert_eq!(grid.sample(Vec2::new(1.0, 1.0)), 0.0);
+        assert_eq!(grid.sample(Vec2::new(3.0, 1.0)), 1.0);
+        assert_eq!(grid.sample(Vec2::new(1.0, 3.0)), 2.0);
+        assert_eq!(grid.sample(Vec2::new(2.0, 2.0)), 1.5);
+        assert_eq!(grid.peak(), Vec2::new(3.0, 3.0));
+    }
+
+    #[test]
+    fn grids_clamp_to_their_edges() {
+        let grid = Grid { origin: Vec2::ONE, cell: 2.0, width: 2, height: 2, values: vec![0.0, 1.0, 2.0, 3.0] };
+        assert_eq!(grid.sample(Vec2::new(-10.0, -10.0)), 0.0);
+        assert_eq!(grid.sample(Vec2::new(10.0, 1.0)), 1.0);
+        assert_eq!(grid.sample(Vec2::new(2.0, 10.0)), 2.5);
+    }
+
+    #[test]
+    fn images_run_bottom_up() {
+        let path = std::env::temp_dir().join(format!("blob-field-{}.png", std::process::id()));
+        image::GrayImage::from_raw(1, 2, vec![255, 0]).unwrap().save(&path).unwrap();
+        let field = Field::Image { path: path.clone(), origin: [1.0, 1.0], cell: 0.5, scale: 2.0 };
+        let field = ScalarField::new(&field);
+        std::fs::remove_file(&path).unwrap();
+        let field = field.unwrap();
+        assert_eq!(field.sample(Vec3::new(1.0, 1.0, 0.0)), 0.0);
+        assert_eq!(field.sample(Vec3::new(1.0, 1.5, 0.0)), 2.0);
+        assert_eq!(field.peak(), Vec2::new(1.0, 1.5));
+    }
+
+    #[test]
+    fn sources_peak_at_the_strongest() {
+        let source = |x: f32, strength: f32| Source { position: [x, 0.0], strength, spread: 1.0 };
+        let field = ScalarField::new(&Field::Sources { sources: vec![source(0.0, 1.0), source(5.0, 2.0)] }).unwrap();
+        assert_eq!(field.peak(), Vec2::new(5.0, 0.0));
+        assert!((field.sample(Vec3::new(5.0, 0.0, 0.0)) - 2.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn gradients_peak_at_their_origin() {
+        let linear = Field::Linear { origin: [1.0, 2.0], value: 3.0, gradient: [0.5, 0.0] };
+        let linear = ScalarField::new(&linear).unwrap();
+        assert_eq!(linear.sample(Vec3::new(3.0, 5.0, 0.0)), 4.0);
+        assert_eq!(linear.peak(), Vec2::new(1.0, 2.0));
+        let radial = ScalarField::new(&Field::Radial { center: [1.0, 0.0], value: 3.0, slope: -1.0 }).unwrap();
+        assert_eq!(radial.sample(Vec3::new(1.0, 2.0, 0.0)), 1.0);
+        assert_eq!(radial.peak(), Vec2::new(1.0, 0.0));
+    }
+}
@@ -0,0 +1,422 @@
+use std::{collections::HashMap, str::FromStr};
+
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    config::{Config, WormSettings},
+    field::ScalarField,
+    muscle::Muscle,
+    physics::{Material, Position, Spring},
+    sensors::{PreferredTemperature, Temperature},
+    step::{SimulationStage, Timestep},
+    worm::{centers_of_mass, Control, Head, WormId},
+    Outcome, TimeTracker,
+};
+
+/// What a worm did during one tick.
+pub struct Sample {
+    pub time: f32,
+    pub com: Vec3,
+    /// Work done by `Control` springs and their muscles during the tick.
+    pub work: f32,
+    /// How far the temperature at the head is from the worm's preferred one,
+    /// when there is a temperature field.
+    pub isotherm: Option<f32>,
+}
+
+/// Saving and restoring a fitness's running totals, for checkpoints.
+pub trait Persist {
+    fn state(&self) -> Vec<u8>;
+    fn restore(&mut self, state: &[u8]) -> Result<(), String>;
+}
+
+impl<T: Serialize + DeserializeOwned> Persist for T {
+    fn state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        *self = bincode::deserialize(state).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+pub trait Fitness: Persist + Send + Sync {
+    fn name(&self) -> &'static str;
+    fn update(&mut self, sample: &Sample);
+    fn value(&self) -> f32;
+}
+
+/// Mean x of the worm's nodes at the end of the run, the historical outcome.
+#[derive(Default, Serialize, Deserialize)]
+pub struct MeanX {
+    x: f32,
+}
+
+impl Fitness for MeanX {
+    fn name(&self) -> &'static str { "mean_x" }
+    fn update(&mut self, sample: &Sample) { self.x = sample.com.x; }
+    fn value(&self) -> f32 { self.x }
+}
+
+/// Straight line distance between the first and last center of mass.
+#[derive(Default, Serialize, Deserialize)]
+pub struct NetDisplacement {
+    start: Option<Vec3>,
+    now: Vec3,
+}
+
+impl Fitness for NetDisplacement {
+    fn name(&self) -> &'static str { "displacement" }
+
+    fn update(&mut self, sample: &Sample) {
+        self.start.get_or_insert(sample.com);
+        self.now = sample.com;
+    }
+
+    fn value(&self) -> f32 {
+        self.start.map_or(0.0, |start| (self.now - start).truncate().length())
+    }
+}
+
+/// Average speed along a fixed direction.
+#[derive(Serialize, Deserialize)]
+pub struct DirectionalSpeed {
+    direction: Vec3,
+    start: Option<(f32, Vec3)>,
+    now: (f32, Vec3),
+}
+
+impl DirectionalSpeed {
+    pub fn new(direction: Vec2) -> Self {
+        Self { direction: direction.normalize_or_zero().extend(0.0), start: None, now: (0.0, Vec3::ZERO) }
+    }
+}
+
+impl Fitness for DirectionalSpeed {
+    fn name(&self) -> &'static str { "speed" }
+
+    fn update(&mut self, sample: &Sample) {
+        self.start.get_or_insert((sample.time, sample.com));
+        self.now = (sample.time, sample.com);
+    }
+
+    fn value(&self) -> f32 {
+        let Some((t0, start)) = self.start else { return 0.0 };
+        let elapsed = self.now.0 - t0;
+        if elapsed <= 0.0 { return 0.0 }
+        (self.now.1 - start).dot(self.direction) / elapsed
+    }
+}
+
+/// Net displacement per unit of work done by the muscles.
+#[derive(Default, Serialize, Deserialize)]
+pub struct EnergyEfficiency {
+    displacement: NetDisplacement,
+    work: f32,
+}
+
+impl Fitness for EnergyEfficiency {
+    fn name(&self) -> &'static str { "efficiency" }
+
+    fn update(&mut self, sample: &Sample) {
+        self.displacement.update(sample);
+        self.work += sample.work;
+    }
+
+    fn value(&self) -> f32 {
+        if self.work <= 0.0 { return 0.0 }
+        self.displacement.value() / self.work
+    }
+}
+
+/// Net displacement over the length of the path the center of mass took,
+/// 1.0 for a perfectly straight run.
+#[derive(Default, Serialize, Deserialize)]
+pub struct PathStraightness {
+    displacement: NetDisplacement,
+    last: Option<Vec3>,
+    path: f32,
+}
+
+impl Fitness for PathStraightness {
+    fn name(&self) -> &'static str { "straightness" }
+
+    fn update(&mut self, sample: &Sample) {
+        self.displacement.update(sample);
+        if let Some(last) = self.last {
+            self.path += (sample.com - last).truncate().length();
+        }
+        self.last = Some(sample.com);
+    }
+
+    fn value(&self) -> f32 {
+        if self.path <= 0.0 { return 0.0 }
+        self.displacement.value() / self.path
+    }
+}
+
+/// How much closer the center of mass ended up to a target point.
+#[derive(Serialize, Deserialize)]
+pub struct TargetReaching {
+    target: Vec3,
+    start: Option<Vec3>,
+    now: Vec3,
+}
+
+impl TargetReaching {
+    pub fn new(target: Vec2) -> Self {
+        Self { target: target.extend(0.0), start: None, now: Vec3::ZERO }
+    }
+}
+
+impl Fitness for TargetReaching {
+    fn name(&self) -> &'static str { "target" }
+
+    fn update(&mut self, sample: &Sample) {
+        self.start.get_or_insert(sample.com);
+        self.now = sample.com;
+    }
+
+    fn value(&self) -> f32 {
+        let Some(start) = self.start else { return 0.0 };
+        let distance = |p: Vec3| (self.target - p).truncate().length();
+        distance(start) - distance(self.now)
+    }
+}
+
+/// How much closer the center of mass ended up to the chemical's peak.
+#[derive(Serialize, Deserialize)]
+pub struct Chemotaxis(TargetReaching);
+
+impl Fitness for Chemotaxis {
+    fn name(&self) -> &'static str { "chemotaxis" }
+    fn update(&mut self, sample: &Sample) { self.0.update(sample); }
+    fn value(&self) -> f32 { self.0.value() }
+}
+
+/// Fraction of the run the head spent within `tolerance` of the preferred
+/// temperature.
+#[derive(Serialize, Deserialize)]
+pub struct IsothermTracking {
+    tolerance: f32,
+    near: usize,
+    samples: usize,
+}
+
+impl IsothermTracking {
+    pub fn new(tolerance: f32) -> Self {
+        Self { tolerance, near: 0, samples: 0 }
+    }
+}
+
+impl Fitness for IsothermTracking {
+    fn name(&self) -> &'static str { "thermotaxis" }
+
+    fn update(&mut self, sample: &Sample) {
+        let Some(isotherm) = sample.isotherm else { return };
+        self.samples += 1;
+        if isotherm <= self.tolerance { self.near += 1; }
+    }
+
+    fn value(&self) -> f32 {
+        if self.samples == 0 { return 0.0 }
+        self.near as f32 / self.samples as f32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FitnessKind {
+    MeanX,
+    Displacement,
+    Speed,
+    Efficiency,
+    Straightness,
+    Target,
+    Chemotaxis,
+    Thermotaxis,
+}
+
+impl FromStr for FitnessKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean_x" => Ok(Self::MeanX),
+            "displacement" => Ok(Self::Displacement),
+            "speed" => Ok(Self::Speed),
+            "efficiency" => Ok(Self::Efficiency),
+            "straightness" => Ok(Self::Straightness),
+            "target" => Ok(Self::Target),
+            "chemotaxis" => Ok(Self::Chemotaxis),
+            "thermotaxis" => Ok(Self::Thermotaxis),
+            _ => Err(format!("unknown fitness `{}`", s)),
+        }
+    }
+}
+
+impl FitnessKind {
+    pub fn build(&self, config: &Config) -> Box<dyn Fitness> {
+        match self {
+            Self::MeanX => Box::new(MeanX::default()),
+            Self::Displacement => Box::new(NetDisplacement::default()),
+            Self::Speed => Box::new(DirectionalSpeed::new(Vec2::from_array(config.direction))),
+            Self::Efficiency => Box::new(EnergyEfficiency::default()),
+            Self::Straightness => Box::new(PathStraightness::default()),
+            Self::Target => Box::new(TargetReaching::new(Vec2::from_array(config.target))),
+            Self::Chemotaxis => {
+                // `Config::check` makes sure the field is there and loads.
+                let peak = config.chemotaxis.field.as_ref()
+                    .and_then(|field| ScalarField::new(field).ok())
+                    .map_or(Vec2::ZERO, |field| field.peak());
+                Box::new(Chemotaxis(TargetReaching::new(peak)))
+            }
+            Self::Thermotaxis => Box::new(IsothermTracking::new(config.thermotaxis.tolerance)),
+        }
+    }
+}
+
+/// Every fitness selected for the run, in `Config::fitness` order; the first
+/// one is the worm's primary score.
+#[derive(Component)]
+pub struct Fitnesses(pub Vec<Box<dyn Fitness>>);
+
+impl Fitnesses {
+    pub fn from_config(config: &Config) -> Self {
+        Self(config.fitness.iter().map(|kind| kind.build(config)).collect())
+    }
+}
+
+/// Length of every `Control` spring at the end of the last tick, which the
+/// work done during this one is measured from.
+#[derive(Resource, Default)]
+pub struct ControlLengths(pub HashMap<Entity, f32>);
+
+#[allow(clippy::too_many_arguments)]
+fn update_fitness(
+    mut worms: Query<(Entity, &mut Fitnesses, Option<&PreferredTemperature>)>,
+    mut lengths: ResMut<ControlLengths>,
+    time: Res<TimeTracker>,
+    nodes: Query<(&Parent, &Position)>,
+    positions: Query<&Position>,
+    springs: Query<(Entity, &Parent, &Spring, Option<&Material>, Option<&Muscle>), With<Control>>,
+    temperature: Option<Res<Temperature>>,
+    heads: Query<(&Parent, &Position), With<Head>>,
+    settings: Res<WormSettings>,
+    timestep: Res<Timestep>
+) {
+    let centers = centers_of_mass(nodes.iter());
+
+    // Broken springs are gone for good.
+    lengths.0.retain(|spring, _| springs.contains(*spring));
+    let mut work: HashMap<Entity, f32> = HashMap::new();
+    for (entity, parent, spring, material, muscle) in springs.iter() {
+        let (Ok(a), Ok(b)) = (positions.get(spring.a), positions.get(spring.b)) else { continue };
+        let diff = a.now - b.now;
+        let length = diff.length();
+        if let Some(last) = lengths.0.insert(entity, length) {
+            let velocity = if length > 0.0 {
+                ((a.now - a.last) - (b.now - b.last)).dot(diff / length) / timestep.physics_dt()
+            } else {
+                0.0
+            };
+            // The same tension `spring_mass_system` applies.
+            let mut tension = match material {
+                Some(material) => material.tension(spring.constant, length - spring.length, velocity),
+                None => spring.constant * (length - spring.length),
+            };
+            if let Some(muscle) = muscle.filter(|_| length > 0.0) {
+                tension += settings.muscle.tension(muscle.activation, length, velocity);
+            }
+            *work.entry(parent.get()).or_default() += (tension * (length - last)).abs();
+        }
+    }
+
+    let heads: HashMap<Entity, Vec3> = heads.iter().map(|(parent, pos)| (parent.get(), pos.now)).collect();
+
+    for (entity, mut fitnesses, preferred) in worms.iter_mut() {
+        let Some(com) = centers.get(&entity) else { continue };
+        let work = work.get(&entity).copied().unwrap_or_default();
+        let isotherm = match (&temperature, preferred, heads.get(&entity)) {
+            (Some(temperature), Some(preferred), Some(head)) => {
+                Some((temperature.0.sample(*head) - preferred.0).abs())
+            }
+            _ => None,
+        };
+        let sample = Sample { time: time.0, com: *com, work, isotherm };
+        for fitness in fitnesses.0.iter_mut() {
+            fitness.update(&sample);
+        }
+    }
+}
+
+fn record_outcome(
+    time: Res<TimeTracker>,
+    config: Res<Config>,
+    worms: Query<(&WormId, &Fitnesses)>,
+    mut outcome: ResMut<Outcome>
+) {
+    if outcome.0.is_none() && time.0 >= config.run_length {
+        let mut worms: Vec<_> = worms.iter().collect();
+        worms.sort_by_key(|(id, _)| **id);
+        outcome.0 = Some(worms.iter()
+            .map(|(_, fitnesses)| fitnesses.0.iter().map(|fitness| fitness.value()).collect())
+            .collect());
+    }
+}
+
+pub struct FitnessPlugin;
+impl Plugin for FitnessPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ControlLengths>();
+        app.add_system_to_stage(SimulationStage, update_fitness.after(crate::increment_time));
+        app.add_system_to_stage(SimulationStage, record_outcome.after(update_fitness));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `path` to `fitness` one tick apart and returns its value.
+    fn run(mut fitness: impl Fitness, path: &[(f32, f32)]) -> f32 {
+        for (i, (x, y)) in path.iter().enumerate() {
+            fitness.update(&Sample { time: i as f32, com: Vec3::new(*x, *y, 0.0), work: 1.0, isotherm: None });
+        }
+        fitness.value()
+    }
+
+    const SQUARE: [(f32, f32); 4] = [(0.0, 0.0), (3.0, 0.0), (3.0, 4.0), (0.0, 4.0)];
+
+    #[test]
+    fn displacement_is_start_to_end() {
+        assert_eq!(run(NetDisplacement::default(), &[]), 0.0);
+        assert_eq!(run(NetDisplacement::default(), &[(0.0, 0.0), (10.0, 10.0), (3.0, 4.0)]), 5.0);
+        assert_eq!(run(NetDisplacement::default(), &SQUARE), 4.0);
+    }
+
+    #[test]
+    fn straightness_is_displacement_over_path() {
+        assert_eq!(run(PathStraightness::default(), &[(1.0, 1.0)]), 0.0);
+        assert_eq!(run(PathStraightness::default(), &[(0.0, 0.0), (1.0, 0.0), (2.0, 0.0)]), 1.0);
+        assert_eq!(run(PathStraightness::default(), &SQUARE), 0.4);
+        assert_eq!(run(PathStraightness::default(), &[(0.0, 0.0), (2.0, 0.0), (0.0, 0.0)]), 0.0);
+    }
+
+    #[test]
+    fn target_reaching_is_distance_gained() {
+        let target = || TargetReaching::new(Vec2::new(6.0, 8.0));
+        assert_eq!(run(target(), &[]), 0.0);
+        assert_eq!(run(target(), &[(0.0, 0.0), (100.0, 0.0), (3.0, 4.0)]), 5.0);
+        assert_eq!(run(target(), &[(0.0, 0.0), (6.0, 8.0)]), 10.0);
+        assert_eq!(run(target(), &[(3.0, 4.0), (0.0, 0.0)]), -5.0);
+    }
+
+    #[test]
+    fn efficiency_divides_by_work() {
+        assert_eq!(run(EnergyEfficiency::default(), &[(0.0, 0.0), (3.0, 4.0)]), 2.5);
+        assert_eq!(run(EnergyEfficiency::default(), &[]), 0.0);
+    }
+}
@@ -0,0 +1,434 @@
+use std::{collections::HashMap, str::FromStr, time::Instant};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    physics::{Force, Locked, Mass, Material, Position, Spring},
+    step::Timestep,
+};
+
+/// Most conjugate gradient iterations per implicit step.
+const CG_ITERATIONS: usize = 64;
+const CG_TOLERANCE: f32 = 1e-6;
+/// Largest relative stretch or compression of a spring the benchmark still
+/// counts as stable.
+const MAX_STRAIN: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Integrator {
+    /// Position Verlet, the original integrator.
+    Verlet,
+    /// Velocity first, then position. With velocity taken as `now - last` this
+    /// is the same update as `Verlet` up to rounding; kept as a reference.
+    SemiImplicitEuler,
+    /// Classic fourth order Runge-Kutta. Springs are re-evaluated at every
+    /// stage, every other force is held at its start of step value.
+    Rk4,
+    /// One linearised backward Euler step for the springs, solved with
+    /// conjugate gradients; every other force stays explicit.
+    ImplicitEuler,
+}
+
+impl Integrator {
+    pub const ALL: [Self; 4] = [Self::Verlet, Self::SemiImplicitEuler, Self::Rk4, Self::ImplicitEuler];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Verlet => "verlet",
+            Self::SemiImplicitEuler => "semi_implicit_euler",
+            Self::Rk4 => "rk4",
+            Self::ImplicitEuler => "implicit_euler",
+        }
+    }
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter()
+            .find(|integrator| integrator.name() == s)
+            .ok_or(format!("unknown integrator `{}`", s))
+    }
+}
+
+type Springs = [(usize, usize, f32, f32, Material)];
+
+fn spring_forces(springs: &Springs, x: &[Vec3], v: &[Vec3]) -> Vec<Vec3> {
+    let mut forces = vec![Vec3::ZERO; x.len()];
+    for &(a, b, constant, length, material) in springs {
+        let diff = x[a] - x[b];
+        let dist = diff.length();
+        if dist == 0.0 { continue }
+        let rate = (v[a] - v[b]).dot(diff) / dist;
+        let force = diff * (-material.tension(constant, dist - length, rate) / dist);
+        forces[a] += force;
+        forces[b] -= force;
+    }
+    forces
+}
+
+fn offset(base: &[Vec3], by: &[Vec3], h: f32) -> Vec<Vec3> {
+    base.iter().zip(by).map(|(base, by)| *base + *by * h).collect()
+}
+
+fn dot(a: &[Vec3], b: &[Vec3]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a.dot(*b)).sum()
+}
+
+fn rk4(
+    x: &[Vec3],
+    v: &[Vec3],
+    inv_mass: &[f32],
+    force: &[Vec3],
+    springs: &Springs,
+    dt: f32
+) -> (Vec<Vec3>, Vec<Vec3>) {
+    let other: Vec<Vec3> = force.iter()
+        .zip(spring_forces(springs, x, v))
+        .map(|(total, spring)| *total - spring)
+        .collect();
+    let accel = |x: &[Vec3], v: &[Vec3]| -> Vec<Vec3> {
+        spring_forces(springs, x, v).iter()
+            .zip(&other)
+            .zip(inv_mass)
+            .map(|((spring, other), w)| (*spring + *other) * *w)
+            .collect()
+    };
+
+    let k1x = v.to_vec();
+    let k1v = accel(x, v);
+    let k2x = offset(v, &k1v, dt / 2.0);
+    let k2v = accel(&offset(x, &k1x, dt / 2.0), &k2x);
+    let k3x = offset(v, &k2v, dt / 2.0);
+    let k3v = accel(&offset(x, &k2x, dt / 2.0), &k3x);
+    let k4x = offset(v, &k3v, dt);
+    let k4v = accel(&offset(x, &k3x, dt), &k4x);
+
+    let combine = |base: &[Vec3], k1: &[Vec3], k2: &[Vec3], k3: &[Vec3], k4: &[Vec3]| -> Vec<Vec3> {
+        (0..base.len())
+            .map(|i| base[i] + (k1[i] + k2[i] * 2.0 + k3[i] * 2.0 + k4[i]) * (dt / 6.0))
+            .collect()
+    };
+    (combine(x, &k1x, &k2x, &k3x, &k4x), combine(v, &k1v, &k2v, &k3v, &k4v))
+}
+
+/// Velocity first, then position, every force held at its start of step value.
+fn semi_implicit_euler(
+    x: &[Vec3],
+    v: &[Vec3],
+    inv_mass: &[f32],
+    force: &[Vec3],
+    dt: f32
+) -> (Vec<Vec3>, Vec<Vec3>) {
+    let v: Vec<Vec3> = (0..v.len()).map(|i| v[i] + force[i] * inv_mass[i] * dt).collect();
+    (offset(x, &v, dt), v)
+}
+
+/// Solves `(M - dt² K) Δv = dt (f + dt K v)` for the velocity change, with `K`
+/// the Jacobian of linear springs of the same constants. Compressed springs
+/// only keep their axial stiffness so the system stays positive definite, and
+/// slack one-sided springs have none; damping and nonlinearity only enter
+/// through `f`.
+fn implicit_euler(
+    x: &[Vec3],
+    v: &[Vec3],
+    inv_mass: &[f32],
+    force: &[Vec3],
+    springs: &Springs,
+    dt: f32
+) -> (Vec<Vec3>, Vec<Vec3>) {
+    let blocks: Vec<Mat3> = springs.iter()
+        .map(|&(a, b, constant, length, material)| {
+            let diff = x[a] - x[b];
+            let dist = diff.length();
+            if dist == 0.0 || !material.engaged(dist - length) { return Mat3::ZERO }
+            let u = diff / dist;
+            let axial = Mat3::from_cols(u * u.x, u * u.y, u * u.z);
+            let stretch = (1.0 - length / dist).max(0.0);
+            (axial + (Mat3::IDENTITY - axial) * stretch) * -constant
+        })
+        .collect();
+    let stiffness = |p: &[Vec3]| -> Vec<Vec3> {
+        let mut out = vec![Vec3::ZERO; p.len()];
+        for (&(a, b, ..), block) in springs.iter().zip(&blocks) {
+            let f = *block * (p[a] - p[b]);
+            out[a] += f;
+            out[b] -= f;
+        }
+        out
+    };
+    // Locked nodes keep their velocity: their rows are the identity with a
+    // zero right hand side.
+    let system = |p: &[Vec3]| -> Vec<Vec3> {
+        stiffness(p).iter()
+            .zip(p)
+            .zip(inv_mass)
+            .map(|((kp, p), w)| if *w == 0.0 { *p } else { *p / *w - *kp * dt * dt })
+            .collect()
+    };
+
+    let kv = stiffness(v);
+    let rhs: Vec<Vec3> = (0..x.len())
+        .map(|i| if inv_mass[i] == 0.0 { Vec3::ZERO } else { (force[i] + kv[i] * dt) * dt })
+        .collect();
+
+    let mut dv = vec![Vec3::ZERO; x.len()];
+    let mut r = rhs;
+    let mut p = r.clone();
+    let mut rr = dot(&r, &r);
+    for _ in 0..CG_ITERATIONS {
+        if rr <= CG_TOLERANCE * CG_TOLERANCE { break }
+        let ap = system(&p);
+        let pap = dot(&p, &ap);
+        if pap <= 0.0 { break }
+        let alpha = rr / pap;
+        dv = offset(&dv, &p, alpha);
+        r = offset(&r, &ap, -alpha);
+        let next = dot(&r, &r);
+        p = offset(&r, &p, next / rr);
+        rr = next;
+    }
+
+    let v = offset(v, &dv, 1.0);
+    (offset(x, &v, dt), v)
+}
+
+/// Every integrator but `Verlet`, which keeps its own system. Velocities are
+/// read from and written back as `now - last`, so the drag systems see the
+/// same representation whichever integrator ran.
+pub fn integrate(
+    config: Res<Config>,
+    timestep: Res<Timestep>,
+    mut nodes: Query<(Entity, &mut Position, &Mass, &Force, Option<&Locked>)>,
+    springs: Query<(&Spring, Option<&Material>)>
+) {
+    if config.integrator == Integrator::Verlet { return }
+    let dt = timestep.physics_dt();
+
+    let index: HashMap<Entity, usize> = nodes.iter()
+        .enumerate()
+        .map(|(i, (entity, ..))| (entity, i))
+        .collect();
+    let mut x = vec![];
+    let mut v = vec![];
+    let mut inv_mass = vec![];
+    let mut force = vec![];
+    for (_, pos, mass, f, locked) in nodes.iter() {
+        let free = locked.is_none() && mass.0 > 0.0;
+        x.push(pos.now);
+        v.push(if free { (pos.now - pos.last) / dt } else { Vec3::ZERO });
+        inv_mass.push(if free { 1.0 / mass.0 } else { 0.0 });
+        force.push(f.0);
+    }
+    let springs: Vec<(usize, usize, f32, f32, Material)> = springs.iter()
+        .filter_map(|(s, material)| Some((
+            *index.get(&s.a)?,
+            *index.get(&s.b)?,
+            s.constant,
+            s.length,
+            material.copied().unwrap_or_default()
+        )))
+        .collect();
+
+    let (x, v) = match config.integrator {
+        Integrator::SemiImplicitEuler => semi_implicit_euler(&x, &v, &inv_mass, &force, dt),
+        Integrator::Rk4 => rk4(&x, &v, &inv_mass, &force, &springs, dt),
+        Integrator::ImplicitEuler => implicit_euler(&x, &v, &inv_mass, &force, &springs, dt),
+        Integrator::Verlet => unreachable!(),
+    };
+
+    for (i, (_, mut pos, _, _, locked)) in nodes.iter_mut().enumerate() {
+        if locked.is_some() { continue }
+        pos.now = x[i];
+        pos.last = x[i] - v[i] * dt;
+    }
+}
+
+/// Largest relative deviation of any spring from its rest length, NaN once
+/// any node has blown up.
+fn max_strain(world: &mut World) -> f32 {
+    let positions: HashMap<Entity, Vec3> = world.query::<(Entity, &Position)>()
+        .iter(world)
+        .map(|(entity, pos)| (entity, pos.now))
+        .collect();
+    let mut worst = 0.0_f32;
+    for spring in world.query::<&Spring>().iter(world) {
+        let (Some(a), Some(b)) = (positions.get(&spring.a), positions.get(&spring.b)) else { continue };
+        let dist = (*a - *b).length();
+        if !dist.is_finite() { return f32::NAN }
+        worst = worst.max(((dist - spring.length) / spring.length).abs());
+    }
+    worst
+}
+
+/// Steps a headless run for `seconds` of experiment time, stopping early once
+/// a spring's strain passes `MAX_STRAIN`. Returns whether it stayed below and
+/// the largest strain seen.
+fn stability(config: Config, seconds: f32) -> (bool, f32) {
+    let ticks = (seconds / config.timestep.tick).ceil() as usize;
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    crate::add_simulation(&mut app, config);
+
+    let mut worst = 0.0_f32;
+    for _ in 0..ticks {
+        app.update();
+        let strain = max_strain(&mut app.world);
+        if strain.is_nan() || strain > MAX_STRAIN { return (false, strain) }
+        worst = worst.max(strain);
+    }
+    (true, worst)
+}
+
+/// `blob bench [--dts 0.01,0.05,...] [--seconds s] [config flags]`
+///
+/// Runs the worm with every integrator at every physics step and prints
+/// `integrator,physics_dt,stable,max_strain,milliseconds` lines, then the
+/// largest step each integrator stayed stable at to stderr.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut dts = vec![0.01, 0.02, 0.05, 0.1, 0.2, 0.5];
+    let mut seconds = 20.0;
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", flag));
+        let invalid = || format!("invalid value for {}", flag);
+        match flag.as_str() {
+            "--dts" => dts = value()?.split(',')
+                .map(|dt| dt.parse().map_err(|_| invalid()))
+                .collect::<Result<_, _>>()?,
+            "--seconds" => seconds = value()?.parse().map_err(|_| invalid())?,
+            _ => rest.push(flag.clone()),
+        }
+    }
+
+    let mut config = Config::from_args(rest)?;
+    config.gui = false;
+    config.log_every_frame = false;
+    config.run_length = f32::INFINITY;
+    config.seed();
+
+    println!("integrator,physics_dt,stable,max_strain,milliseconds");
+    let mut largest = vec![];
+    for integrator in Integrator::ALL {
+        let mut stable_dt = None::<f32>;
+        for dt in &dts {
+            let mut config = config.clone();
+            config.integrator = integrator;
+            config.timestep.physics_dt = *dt;
+            let start = Instant::now();
+            let (stable, strain) = stability(config, seconds);
+            println!(
+                "{},{},{},{},{}",
+                integrator.name(), dt, stable, strain, start.elapsed().as_millis()
+            );
+            if stable { stable_dt = Some(stable_dt.map_or(*dt, |largest| largest.max(*dt))); }
+        }
+        largest.push((integrator, stable_dt));
+    }
+    for (integrator, dt) in largest {
+        match dt {
+            Some(dt) => eprintln!("{} is stable up to physics_dt {}", integrator.name(), dt),
+            None => eprintln!("{} is stable at none of the steps tried", integrator.name()),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Step = fn(&[Vec3], &[Vec3], &[f32], &[Vec3], &Springs, f32) -> (Vec<Vec3>, Vec<Vec3>);
+
+    fn semi_implicit(
+        x: &[Vec3],
+        v: &[Vec3],
+        inv_mass: &[f32],
+        force: &[Vec3],
+        _: &Springs,
+        dt: f32
+    ) -> (Vec<Vec3>, Vec<Vec3>) {
+        semi_implicit_euler(x, v, inv_mass, force, dt)
+    }
+
+    /// Two unit masses on a unit spring of rest length 1, released 0.1
+    /// stretched: `ω = √2`. Returns the extension and energy after each step.
+    fn two_nodes(step: Step, dt: f32, steps: usize) -> Vec<(f32, f32)> {
+        let springs = [(0, 1, 1.0, 1.0, Material::default())];
+        let inv_mass = [1.0, 1.0];
+        let mut x = vec![Vec3::ZERO, Vec3::new(1.1, 0.0, 0.0)];
+        let mut v = vec![Vec3::ZERO; 2];
+        (0..steps)
+            .map(|_| {
+                let force = spring_forces(&springs, &x, &v);
+                (x, v) = step(&x, &v, &inv_mass, &force, &springs, dt);
+                let extension = (x[1] - x[0]).length() - 1.0;
+                (extension, 0.5 * (dot(&v, &v) + extension * extension))
+            })
+            .collect()
+    }
+
+    fn largest_extension(step: Step, dt: f32) -> f32 {
+        two_nodes(step, dt, 200).iter()
+            .map(|(x, _)| if x.is_nan() { f32::INFINITY } else { x.abs() })
+            .fold(0.0, f32::max)
+    }
+
+    const ENERGY: f32 = 0.005;
+    const PERIOD: f32 = std::f32::consts::TAU / std::f32::consts::SQRT_2;
+
+    #[test]
+    fn rk4_keeps_energy_and_period() {
+        let run = two_nodes(rk4, 0.01, (PERIOD / 0.01).round() as usize);
+        assert!(run.iter().all(|(_, energy)| (energy - ENERGY).abs() < 1e-6));
+        assert!((run.last().unwrap().0 - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn semi_implicit_euler_keeps_energy_bounded() {
+        let run = two_nodes(semi_implicit, 0.01, 10 * (PERIOD / 0.01).round() as usize);
+        assert!(run.iter().all(|(_, energy)| (energy - ENERGY).abs() < 0.02 * ENERGY));
+        assert!((run.last().unwrap().0 - 0.1).abs() < 1e-2);
+    }
+
+    #[test]
+    fn implicit_euler_only_loses_energy() {
+        for dt in [0.01, 0.1, 10.0] {
+            let run = two_nodes(implicit_euler, dt, 200);
+            let mut last = ENERGY;
+            for (_, energy) in run {
+                assert!(energy <= last * (1.0 + 1e-4) + 1e-12, "dt {}", dt);
+                last = energy;
+            }
+            assert!(last < ENERGY);
+        }
+    }
+
+    #[test]
+    fn explicit_integrators_blow_up_past_their_limit() {
+        let verlet_limit = std::f32::consts::SQRT_2;
+        assert!(largest_extension(semi_implicit, 0.9 * verlet_limit) < 1.0);
+        assert!(largest_extension(semi_implicit, 1.1 * verlet_limit) > 1e3);
+        assert!(largest_extension(rk4, 1.9) < 1.0);
+        assert!(largest_extension(rk4, 2.1) > 1e3);
+        assert!(largest_extension(implicit_euler, 100.0) <= 0.1);
+    }
+
+    #[test]
+    fn slack_springs_add_no_implicit_stiffness() {
+        let rope = Material { mode: crate::physics::SpringMode::TensionOnly, ..default() };
+        let springs = [(0, 1, 100.0, 1.0, rope)];
+        let x = [Vec3::ZERO, Vec3::new(0.5, 0.0, 0.0)];
+        let v = [Vec3::new(-1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)];
+        let force = spring_forces(&springs, &x, &v);
+        let (x, after) = implicit_euler(&x, &v, &[1.0, 1.0], &force, &springs, 0.1);
+        assert_eq!(after, v);
+        assert_eq!(x, vec![Vec3::new(-0.1, 0.0, 0.0), Vec3::new(0.6, 0.0, 0.0)]);
+    }
+}
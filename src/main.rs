@@ -5,6 +5,8 @@ use bevy::prelude::*;
 use bevy::sprite::MaterialMesh2dBundle;
 use bevy_pancam::*;
 use bevy_prototype_debug_lines::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 
 mod physics;
 mod vector;
@@ -12,23 +14,34 @@ mod worm;
 mod grid;
 mod brain;
 mod ui;
+mod config;
+mod sweep;
+mod step;
+mod recording;
+mod replay;
+mod checkpoint;
+mod evolve;
+mod fitness;
+mod collision;
+mod scene;
+mod medium;
+mod integrator;
+mod muscle;
+mod body;
+mod blob;
+mod pressure;
+mod field;
+mod sensors;
 
+use config::{Config, Mapping, WormSettings};
 use grid::draw_grid;
 use physics::*;
+use step::{SimulationStage, Timestep};
 use worm::WormController;
 
-pub const HISTORY_LENGTH: usize = 500;
 pub const DRAW_GRID: bool = false;
 pub const DRAW_UI: bool = true;
 pub const EDGE_COLORS: bool = false;
-pub const LOG_EVERY_FRAME: bool = true;
-
-pub const DEVO_BRAIN: bool = false;
-pub const DEVO_BODY: bool = false;
-pub const MAPPING_CYCLICAL: bool = true;
-
-#[derive(Component)]
-struct Log;
 
 #[derive(Resource, Default)]
 pub struct TimeTracker(f32);
@@ -36,81 +49,133 @@ pub struct TimeTracker(f32);
 pub struct TimeTracker2(f32);
 #[derive(Resource, Default)]
 pub struct TimeTrackerInt(i32);
+/// Center of mass a worm's displacement is measured from, taken on its first
+/// tick.
+#[derive(Component, Default)]
+pub struct InitialPosition(Option<Vec3>);
+/// The only source of randomness in the simulation, seeded from `Config::seed`.
+#[derive(Resource)]
+pub struct SimRng(pub ChaCha8Rng);
+/// Brain every worm is spawned with, taking precedence over `Config::brain`.
+#[derive(Resource)]
+pub struct StartingBrain(pub brain::BrainSnapshot);
+/// Every selected fitness of every worm once `run_length` seconds have been
+/// simulated, in `WormId` and then `Config::fitness` order.
 #[derive(Resource, Default)]
-pub struct InitialPosition(Vec3);
+pub struct Outcome(pub Option<Vec<Vec<f32>>>);
 
-#[derive(Resource, Default)]
-pub struct WormSettings {
-    frequency: f32,
-    phase: f32,
-    neurons: usize,
-    segments: usize
-}
+/// Seconds between the segments and neurons a developing worm grows.
+pub const GROWTH_INTERVAL: i32 = 60;
 
-#[derive(Resource, Default)]
+/// Segments and neurons waiting to be grown onto a worm.
+#[derive(Component, Default)]
 pub struct Adder {
     segment: usize,
     neuron: usize
 }
 
-fn setup(mut commands: Commands, worm_settings: Res<WormSettings>) {
+/// Whether a worm grows its brain and body over time.
+#[derive(Component)]
+pub struct Development {
+    brain: bool,
+    body: bool
+}
+
+/// Worm the keyboard and the UI act on, by `WormId`.
+#[derive(Resource, Default)]
+pub struct SelectedWorm(pub usize);
+
+fn default_control(p: f32, t: f32, time: f32, index: f32, side: f32) -> f32 {
+    let phase = index * PI / p;
+    let u = (-time * 60.0 / t + phase).sin() * side;
+    0.5 + u * 0.2
+}
+
+pub fn default_controller(time: f32, index: f32, side: f32) -> f32 {
+    // default_control(3.0, 50.0, time, index, side)
+    default_control(6.0, 200.0, time, index, side)
+}
+
+fn setup(
+    mut commands: Commands,
+    mut rng: ResMut<SimRng>,
+    config: Res<Config>,
+    worm_settings: Res<WormSettings>,
+    starting_brain: Option<Res<StartingBrain>>
+) {
+    if config.resume.is_some() { return }
+    for (id, spec) in config.worms().into_iter().enumerate() {
+        let devo_brain = spec.devo_brain.unwrap_or(config.devo_brain);
+        // Blobs have no segments to grow.
+        let devo_body = spec.devo_body.unwrap_or(config.devo_body) && spec.blob.is_none();
+        let brain = match (&starting_brain, spec.brain.as_ref().or(config.brain.as_ref())) {
+            (Some(brain), _) => brain.0.restore(),
+            (None, Some(path)) => brain::BrainSnapshot::load(path)
+                .unwrap_or_else(|err| unreachable!("`Config::check` loads every brain: {}", err))
+                .restore(),
+            (None, None) => brain::CTRNN::trained(devo_brain, &mut rng.0),
+        };
+        let len = spec.segments.unwrap_or(
+            if devo_body { worm_settings.segments } else { worm_settings.adult_segments }
+        );
+        let position = Vec2::from_array(spec.position).extend(0.0);
+        let worm = match &spec.blob {
+            Some(shape) => blob::blob_builder(
+                shape, position, &mut commands, default_controller, worm_settings.neurons, brain,
+                &worm_settings.springs
+            ),
+            None => worm::worm_builder(
+                len, position, &mut commands, default_controller, worm_settings.neurons, brain,
+                &worm_settings
+            ),
+        };
+        commands.entity(worm).insert((
+            worm::WormId(id),
+            InitialPosition::default(),
+            Adder::default(),
+            Development { brain: devo_brain, body: devo_body },
+            sensors::PreferredTemperature(
+                spec.preferred_temperature.unwrap_or(config.thermotaxis.preferred)
+            ),
+            fitness::Fitnesses::from_config(&config)
+        ));
+        // commands.entity(worm).insert(worm::ManualControl);
+        match spec.mapping.unwrap_or(config.mapping) {
+            Mapping::Cyclical => commands.entity(worm).insert(worm::CyclicalMapping),
+            Mapping::Regional => commands.entity(worm).insert(worm::RegionalMapping),
+        };
+        // commands.entity(worm).insert(worm::FrequencyMapping {
+        //     frequency: worm_settings.frequency,
+        //     phase: worm_settings.phase,
+        // });
+    }
+}
+
+fn spawn_camera(mut commands: Commands) {
     commands.spawn(Camera2dBundle {
         projection: OrthographicProjection { scale: 0.02, ..default() },
         transform: Transform::default().with_translation(Vec3::Z),
         ..default()
     }).insert(PanCam::default());
-
-    fn default_control(p: f32, t: f32, time: f32, index: f32, side: f32) -> f32 {
-        let phase = index * PI / p;
-        let u = (-time * 60.0 / t + phase).sin() * side;
-        0.5 + u * 0.2
-    }
-
-    let len = if DEVO_BODY { worm_settings.segments } else { 12 };
-    let worm = worm::worm_builder(len, Vec3::ZERO, &mut commands, |time, index, side| {
-        // default_control(3.0, 50.0, time, index, side)
-        default_control(6.0, 200.0, time, index, side)
-    }, worm_settings.neurons);
-    // commands.entity(worm).insert(worm::ManualControl);
-    if MAPPING_CYCLICAL {
-        commands.entity(worm).insert(worm::CyclicalMapping);
-    } else {
-        commands.entity(worm).insert(worm::RegionalMapping);
-    }
-    // commands.entity(worm).insert(worm::FrequencyMapping {
-    //     frequency: worm_settings.frequency,
-    //     phase: worm_settings.phase,
-    // });
-    // commands.entity(worm)
-        // .insert(worm::CyclicalMapping)
-        // .insert(brain::UpdateFlux)
-        // .insert(brain::LogCTRNN)
-    // ;
-
-    // worm::worm_builder(15, Vec3::new(0., -4., 0.), &mut commands, |time, index, side| {
-    //     default_control(6.0, 250.0, time, index, side)
-    // });
-
-    // worm::worm_builder(15, Vec3::new(0., 4., 0.), &mut commands, |time, index, side| {
-    //     default_control(6.0, 200.0, time, index, side)
-    // });
 }
 
 fn sync_points(
-    mut query: Query<(Entity, &Position, Option<&mut Transform>)>,
+    mut query: Query<(Entity, &Position, Option<&Radius>, Option<&mut Transform>)>,
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ColorMaterial>>,
 ) {
-    for (entity, pos, transform) in query.iter_mut() {
+    for (entity, pos, radius, transform) in query.iter_mut() {
         if let Some(mut transform) = transform {
             transform.translation.x = pos.now.x;
             transform.translation.y = pos.now.y;
             transform.translation.z = 1.0;
         } else {
+            // The default circle has a radius of 0.5.
+            let scale = radius.map_or(0.2, |radius| radius.0 * 2.0);
             commands.entity(entity).insert(MaterialMesh2dBundle {
                 mesh: meshes.add(Mesh::from(shape::Circle::default())).into(),
-                transform: Transform::default().with_scale(Vec3::splat(0.2)),
+                transform: Transform::default().with_scale(Vec3::splat(scale)),
                 material: materials.add(ColorMaterial::from(Color::BLACK)),
                 ..default()
             });
@@ -119,13 +184,13 @@ fn sync_points(
 }
 
 fn sync_edges_cyclical(
-    worms: Query<(&WormController, &worm::Neurons), With<worm::CyclicalMapping>>,
+    worms: Query<&worm::Neurons, With<worm::CyclicalMapping>>,
     query: Query<(&Parent, &Spring, Option<&worm::Control>), Without<worm::SpringHidden>>,
     transforms: Query<&GlobalTransform>,
     mut lines: ResMut<DebugLines>
 ) {
     for (parent, spring, control) in query.iter() {
-        if let Ok((_worm, neurons)) = worms.get(parent.get()) {
+        if let Ok(neurons) = worms.get(parent.get()) {
             let a = match transforms.get(spring.a) {
                 Ok(t) => t.translation(),
                 Err(_) => Vec3::ZERO
@@ -195,171 +260,228 @@ fn sync_edges_regional(
     }
 }
 
-fn increment_time(mut time: ResMut<TimeTracker>) {
-    time.0 += 1.0 / 60.0;
+fn increment_time(mut time: ResMut<TimeTracker>, timestep: Res<Timestep>) {
+    time.0 += timestep.tick();
 }
 
+/// Prints the outcome alone when a single worm is scored by a single fitness,
+/// otherwise one `name,value` line per fitness, prefixed by the worm when
+/// there are several.
 fn log_output_and_exit(
-    time: Res<TimeTracker>,
+    outcome: Res<Outcome>,
+    fitnesses: Query<&fitness::Fitnesses>,
+    config: Res<Config>,
     mut exit: EventWriter<AppExit>,
-    positions: Query<&Position>
 ) {
-    if time.0 >= 600.0 {
-        if !LOG_EVERY_FRAME {
-            let mut total = Vec3::default();
-            let mut count = 0;
-            for pos in positions.iter() {
-                total += pos.now;
-                count += 1;
+    if let Some(scores) = &outcome.0 {
+        if !config.log_every_frame {
+            let names: Vec<&str> = fitnesses.iter().next()
+                .map_or(vec![], |f| f.0.iter().map(|fitness| fitness.name()).collect());
+            match scores.as_slice() {
+                [worm] => match worm.as_slice() {
+                    [x] => println!("{}", x),
+                    _ => for (name, x) in names.iter().zip(worm) {
+                        println!("{},{}", name, x);
+                    },
+                },
+                _ => for (id, worm) in scores.iter().enumerate() {
+                    for (name, x) in names.iter().zip(worm) {
+                        println!("{},{},{}", id, name, x);
+                    }
+                },
             }
-            println!("{}", total.x / count as f32);
         }
         exit.send(AppExit);
     }
 }
 
-fn log_output(
-    time: Res<TimeTracker>,
-    mut time_t: ResMut<TimeTracker2>,
-    initial: Res<InitialPosition>,
-    positions: Query<&Position>
+pub fn select_worm_on_keypress(
+    mut selected: ResMut<SelectedWorm>,
+    worms: Query<&worm::WormId>,
+    keys: Res<Input<KeyCode>>
 ) {
-    let t = (time.0 * 10.0).floor() / 10.0;
-    if t <= time_t.0 { return }
-    time_t.0 = t;
-
-    let mut total = Vec3::default();
-    let mut count = 0;
-    for pos in positions.iter() {
-        total += pos.now;
-        count += 1;
-    }
-    total /= count as f32;
-    total -= initial.0;
-    println!("{},{}", t, total.x.hypot(total.y));
-}
-
-fn logger(positions: Query<&Position, With<Log>>) {
-    for pos in positions.iter() {
-        println!("{:?}", pos.now);
+    if keys.just_pressed(KeyCode::Tab) {
+        let count = worms.iter().count().max(1);
+        selected.0 = (selected.0 + 1) % count;
     }
 }
 
 pub fn adder_on_keypress(
-    mut adder: ResMut<crate::Adder>,
+    selected: Res<SelectedWorm>,
+    mut worms: Query<(&worm::WormId, &mut Adder)>,
     keys: Res<Input<KeyCode>>
 ) {
-    if keys.just_pressed(KeyCode::Space) {
-        adder.segment += 1;
-    }
-    if keys.just_pressed(KeyCode::N) {
-        adder.neuron += 1;
+    for (id, mut adder) in worms.iter_mut() {
+        if id.0 != selected.0 { continue }
+        if keys.just_pressed(KeyCode::Space) {
+            adder.segment += 1;
+        }
+        if keys.just_pressed(KeyCode::N) {
+            adder.neuron += 1;
+        }
     }
 }
 
 pub fn devo_timer(
-    mut adder: ResMut<crate::Adder>,
+    mut worms: Query<(&mut Adder, &Development)>,
     mut time_int: ResMut<TimeTrackerInt>,
     time: Res<TimeTracker>
 ) {
     let time = time.0 as i32;
     let t = time_int.0;
-    if time > t + 60 {
-        time_int.0 += 60;
-        if DEVO_BRAIN { adder.neuron += 1; }
-        if DEVO_BODY { adder.segment += 1; }
+    if time > t + GROWTH_INTERVAL {
+        time_int.0 += GROWTH_INTERVAL;
+        for (mut adder, development) in worms.iter_mut() {
+            if development.brain { adder.neuron += 1; }
+            if development.body { adder.segment += 1; }
+        }
     }
 }
 
 pub fn set_initial_pos(
-    // time: Res<TimeTracker2>,
-    mut pos: ResMut<InitialPosition>,
-    positions: Query<&Position>
+    mut worms: Query<(Entity, &mut InitialPosition)>,
+    nodes: Query<(&Parent, &Position)>
 ) {
-    if pos.0 == Vec3::ZERO {
-        let mut total = Vec3::default();
-        let mut count = 0;
-        for pos in positions.iter() {
-            total += pos.now;
-            count += 1;
+    let centers = worm::centers_of_mass(nodes.iter());
+    for (entity, mut pos) in worms.iter_mut() {
+        if pos.0.is_none() {
+            pos.0 = centers.get(&entity).copied();
         }
-        total /= count as f32;
-        pos.0 = total;
     }
 }
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    let frequency = 0.1;
-    let frequency: f32 = match args.get(1) {
-        Some(num) => num.parse().unwrap_or(frequency),
-        None => frequency,
-    };
-    let phase = 2.0 * PI / 6.0;
-    let phase: f32 = match args.get(2) {
-        Some(num) => num.parse().unwrap_or(phase),
-        None => phase,
+/// Adds everything needed to step the simulation, without any windowing,
+/// printing or exit handling, so it can be shared with batch runners.
+pub fn add_simulation(app: &mut App, mut config: Config) {
+    let seed = config.seed();
+    let scene = match &config.scene {
+        Some(path) => scene::Scene::load(path)
+            .unwrap_or_else(|err| unreachable!("`Config::check` loads the scene: {}", err)),
+        None => scene::Scene::default(),
     };
-    let neurons = 6;
-    let neurons: usize = match args.get(3) {
-        Some(num) => num.parse().unwrap_or(neurons),
-        None => neurons,
-    };
-    let segments = 2;
-    let segments: usize = match args.get(4) {
-        Some(num) => num.parse().unwrap_or(segments),
-        None => segments,
+    let medium = medium::Medium::new(&config.medium)
+        .unwrap_or_else(|err| unreachable!("`Config::check` builds the medium: {}", err));
+    if let Some(field) = &config.chemotaxis.field {
+        let field = field::ScalarField::new(field).unwrap_or_else(|err| panic!("{}", err));
+        app.insert_resource(sensors::Chemical(field));
+    }
+    if let Some(field) = &config.thermotaxis.field {
+        let field = field::ScalarField::new(field).unwrap_or_else(|err| panic!("{}", err));
+        app.insert_resource(sensors::Temperature(field));
+    }
+    app
+        .add_plugin(step::StepPlugin)
+        .insert_resource(SimRng(ChaCha8Rng::seed_from_u64(seed)))
+        .insert_resource(config.timestep)
+        .insert_resource(TimeTracker(0.0))
+        .insert_resource(TimeTracker2(-1.0))
+        .insert_resource(TimeTrackerInt(0))
+        .insert_resource(Outcome::default())
+        .insert_resource(scene::Obstacles(scene.obstacles))
+        .insert_resource(medium)
+        .insert_resource(config.worm.clone())
+        .insert_resource(config)
+        .add_system_to_stage(SimulationStage, increment_time.after(Integration))
+        .add_plugin(physics::PhysicsPlugin)
+        .add_plugin(worm::WormPlugin)
+        .add_plugin(muscle::MusclePlugin)
+        .add_plugin(pressure::PressurePlugin)
+        .add_plugin(brain::BrainPlugin)
+        .add_plugin(sensors::SensorPlugin)
+        .add_plugin(fitness::FitnessPlugin)
+        .add_plugin(checkpoint::CheckpointPlugin)
+        .add_system_to_stage(SimulationStage, devo_timer)
+        .add_system_to_stage(SimulationStage, set_initial_pos.before(brain::Sensing))
+        .add_startup_system(setup);
+}
+
+/// Window, camera and the systems that draw nodes and springs.
+pub fn add_rendering(app: &mut App) {
+    // app.insert_resource(ClearColor(Color::rgb(0.2, 0.2, 0.2)))
+    app.insert_resource(ClearColor(Color::rgb(1.0, 1.0, 1.0)))
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            window: WindowDescriptor {
+                fit_canvas_to_parent: true,
+                ..default()
+            },
+            ..default()
+        }))
+        .add_system(bevy::window::close_on_esc)
+        // .add_plugin(WorldInspectorPlugin::new())
+        .add_plugin(PanCamPlugin::default())
+        .add_plugin(DebugLinesPlugin::with_depth_test(true))
+        .add_startup_system(spawn_camera)
+        .add_system(sync_points)
+        .add_system(sync_edges_cyclical)
+        .add_system(sync_edges_regional)
+        .add_system(scene::draw_obstacles);
+    if DRAW_GRID { app.add_system(draw_grid); }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let mode = match args.first().map(String::as_str) {
+        Some("sweep") => Some(sweep::run as fn(&[String]) -> Result<(), String>),
+        Some("replay") => Some(replay::run as fn(&[String]) -> Result<(), String>),
+        Some("evolve") => Some(evolve::run as fn(&[String]) -> Result<(), String>),
+        Some("bench") => Some(integrator::run as fn(&[String]) -> Result<(), String>),
+        _ => None,
     };
-    let nogui = match args.last() {
-        Some(text) => if text == "--nogui" { true } else { false },
-        None => false,
+    if let Some(run) = mode {
+        if let Err(err) = run(&args[1..]) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let (mut config, resume) = match Config::with_checkpoint(args) {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
     };
 
     let mut app = App::new();
-    let using_gui = !nogui;
-    if using_gui {
-        // app.insert_resource(ClearColor(Color::rgb(0.2, 0.2, 0.2)))
-        app.insert_resource(ClearColor(Color::rgb(1.0, 1.0, 1.0)))
-            .add_plugins(DefaultPlugins.set(WindowPlugin {
-                window: WindowDescriptor {
-                    fit_canvas_to_parent: true,
-                    ..default()
-                },
-                ..default()
-            }))
-            .add_system(bevy::window::close_on_esc)
-            // .add_plugin(WorldInspectorPlugin::new())
-            .add_plugin(PanCamPlugin::default())
-            .add_plugin(DebugLinesPlugin::with_depth_test(true))
-            .add_system(sync_points)
-            .add_system(sync_edges_cyclical)
-            .add_system(sync_edges_regional)
-            .add_system(adder_on_keypress);
+    if config.gui {
+        add_rendering(&mut app);
+        app
+            .init_resource::<SelectedWorm>()
+            .add_system(select_worm_on_keypress)
+            .add_system(adder_on_keypress.after(select_worm_on_keypress));
         if DRAW_UI { app.add_plugin(ui::UIPlugin); }
-        if DRAW_GRID { app.add_system(draw_grid); }
     } else {
         app.add_plugins(MinimalPlugins);
     }
 
-    app
-        .insert_resource(TimeTracker(0.0))
-        .insert_resource(TimeTracker2(-1.0))
-        .insert_resource(TimeTrackerInt(0))
-        .insert_resource(InitialPosition(Vec3::ZERO))
-        .insert_resource(Adder::default())
-        .insert_resource(WormSettings { frequency, phase, neurons, segments })
-        .add_system(increment_time)
-        .add_system(log_output_and_exit)
-        .add_plugin(physics::PhysicsPlugin)
-        .add_plugin(worm::WormPlugin)
-        .add_plugin(brain::BrainPlugin)
-        .add_system(devo_timer)
-        .add_system(set_initial_pos)
-        .add_startup_system(setup)
-        .add_system(logger);
+    config.seed();
+    let recorder = match recording::Recorder::open(&config) {
+        Ok(recorder) => recorder,
+        Err(err) => {
+            eprintln!("could not open recording channels: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let replay = config.replay.clone();
+    let (tick, seed) = (config.timestep.tick(), config.seed);
 
-    if LOG_EVERY_FRAME {
-        app.add_system(log_output);
+    add_simulation(&mut app, config);
+    if let Some(checkpoint) = resume {
+        app.insert_resource(checkpoint::Resume(checkpoint));
+    }
+    app
+        .insert_resource(recorder)
+        .add_plugin(recording::RecordingPlugin)
+        .add_system(log_output_and_exit);
+    if let Some(path) = replay {
+        let obstacles = app.world.resource::<scene::Obstacles>().0.clone();
+        let replay = replay::ReplayWriter::create(&path, tick, seed, obstacles).unwrap_or_else(|err| {
+            eprintln!("could not create {}: {}", path.display(), err);
+            std::process::exit(1);
+        });
+        app.insert_resource(replay).add_plugin(replay::ReplayRecorderPlugin);
     }
 
     app
@@ -0,0 +1,165 @@
+use std::{f32::consts::TAU, fs, path::{Path, PathBuf}};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::field::Grid;
+
+/// Background velocity of the fluid, in units per second.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Flow {
+    Still,
+    /// The same current everywhere.
+    Uniform { velocity: [f32; 2] },
+    /// Point vortex of circulation `strength` (counter-clockwise when
+    /// positive), turning like a solid body within `core` of the center.
+    Vortex { center: [f32; 2], strength: f32, core: f32 },
+    /// Flow along x growing with y, `rate` units per second per unit of y.
+    Shear { rate: f32 },
+    /// Sampled from a grid file, see `FlowGrid`.
+    Grid { path: PathBuf },
+}
+
+/// Everything about the fluid that is read from the config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediumSettings {
+    /// Scales the quadratic drag on nodes.
+    pub density: f32,
+    /// Scales the linear drag across springs.
+    pub viscosity: f32,
+    pub flow: Flow,
+}
+
+impl Default for MediumSettings {
+    fn default() -> Self {
+        Self { density: 1.0, viscosity: 1.0, flow: Flow::Still }
+    }
+}
+
+/// A grid file of velocities, stored row by row from `origin` upwards.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlowGrid {
+    pub origin: [f32; 2],
+    pub cell: f32,
+    pub width: usize,
+    pub height: usize,
+    pub velocities: Vec<[f32; 2]>,
+}
+
+impl FlowGrid {
+    pub fn load(path: impl AsRef<Path>) -> Result<Grid<Vec2>, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        let file: Self = toml::from_str(&text)
+            .map_err(|e| format!("could not parse {}: {}", path.display(), e))?;
+        if file.width == 0 || file.height == 0 || file.velocities.len() != file.width * file.height {
+            return Err(format!("{} should hold width * height velocities", path.display()));
+        }
+        if !(file.cell > 0.0) {
+            return Err(format!("{} needs a positive cell size", path.display()));
+        }
+        let velocities = file.velocities.into_iter().map(Vec2::from_array).collect();
+        Ok(Grid::new(Vec2::from_array(file.origin), file.cell, file.width, file.height, velocities))
+    }
+}
+
+/// The fluid the worms move through; drag acts on velocity relative to it.
+#[derive(Resource)]
+pub struct Medium {
+    pub density: f32,
+    pub viscosity: f32,
+    flow: Flow,
+    grid: Option<Grid<Vec2>>,
+}
+
+impl Medium {
+    pub fn new(settings: &MediumSettings) -> Result<Self, String> {
+        if !(settings.density >= 0.0 && settings.viscosity >= 0.0) {
+            return Err("the medium's density and viscosity cannot be negative".to_string());
+        }
+        if let Flow::Vortex { core, .. } = settings.flow {
+            if !(core >= 0.0) { return Err("a vortex core cannot be negative".to_string()) }
+        }
+        let grid = match &settings.flow {
+            Flow::Grid { path } => Some(FlowGrid::load(path)?),
+            _ => None,
+        };
+        Ok(Self {
+            density: settings.density,
+            viscosity: settings.viscosity,
+            flow: settings.flow.clone(),
+            grid,
+        })
+    }
+
+    /// Velocity of the fluid at `point`.
+    pub fn velocity(&self, point: Vec3) -> Vec3 {
+        let point = point.truncate();
+        let velocity = match &self.flow {
+            Flow::Still => Vec2::ZERO,
+            Flow::Uniform { velocity } => Vec2::from_array(*velocity),
+            Flow::Vortex { center, strength, core } => {
+                let offset = point - Vec2::from_array(*center);
+                let r_sq = offset.length_squared().max(core * core);
+                if r_sq == 0.0 { return Vec3::ZERO }
+                offset.perp() * *strength / (TAU * r_sq)
+            }
+            Flow::Shear { rate } => Vec2::new(rate * point.y, 0.0),
+            Flow::Grid { .. } => self.grid.as_ref().map_or(Vec2::ZERO, |grid| grid.sample(point)),
+        };
+        velocity.extend(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn medium(flow: Flow) -> Medium {
+        Medium::new(&MediumSettings { flow, ..default() }).unwrap()
+    }
+
+    #[test]
+    fn flows_move_the_right_way() {
+        let point = Vec3::new(1.0, 2.0, 0.0);
+        assert_eq!(medium(Flow::Still).velocity(point), Vec3::ZERO);
+        assert_eq!(medium(Flow::Uniform { velocity: [-0.5, 0.25] }).velocity(point), Vec3::new(-0.5, 0.25, 0.0));
+        assert_eq!(medium(Flow::Shear { rate: 0.5 }).velocity(point), Vec3::new(1.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn vortices_turn_solidly_inside_their_core() {
+        let vortex = medium(Flow::Vortex { center: [1.0, 0.0], strength: TAU, core: 0.1 });
+        let outside = vortex.velocity(Vec3::new(3.0, 0.0, 0.0));
+        assert!((outside - Vec3::new(0.0, 0.5, 0.0)).length() < 1e-6);
+        let inside = vortex.velocity(Vec3::new(1.05, 0.0, 0.0));
+        assert!((inside - Vec3::new(0.0, 5.0, 0.0)).length() < 1e-4);
+        assert_eq!(vortex.velocity(Vec3::new(1.0, 0.0, 0.0)), Vec3::ZERO);
+    }
+
+    #[test]
+    fn flow_grids_load_and_interpolate() {
+        let path = std::env::temp_dir().join(format!("blob-flow-{}.toml", std::process::id()));
+        let text = "origin = [0.0, 0.0]\ncell = 2.0\nwidth = 2\nheight = 1\nvelocities = [[0.0, 0.0], [2.0, -2.0]]\n";
+        fs::write(&path, text).unwrap();
+        let grid = medium(Flow::Grid { path: path.clone() });
+        fs::write(&path, text.replace("width = 2", "width = 3")).unwrap();
+        let short = FlowGrid::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(grid.velocity(Vec3::new(1.0, 5.0, 0.0)), Vec3::new(1.0, -1.0, 0.0));
+        assert!(short.is_err());
+        assert!(FlowGrid::load("/nonexistent/flow.toml").is_err());
+    }
+
+    #[test]
+    fn negative_media_are_rejected() {
+        assert!(Medium::new(&MediumSettings { viscosity: -1.0, ..default() }).is_err());
+        assert!(Medium::new(&MediumSettings { density: f32::NAN, ..default() }).is_err());
+        let vortex = Flow::Vortex { center: [0.0, 0.0], strength: 1.0, core: -1.0 };
+        assert!(Medium::new(&MediumSettings { flow: vortex, ..default() }).is_err());
+    }
+}
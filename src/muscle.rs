@@ -0,0 +1,203 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    brain::Sensing,
+    config::WormSettings,
+    physics::{Force, Position, Spring},
+    step::{SimulationStage, Timestep},
+    worm::{Actuation, Control},
+};
+
+/// Hill-type muscle parameters shared by every `Control` spring. Times and
+/// speeds are in physics time, the same units as `physics_dt`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MuscleSettings {
+    /// Drive control springs through muscles instead of setting their length.
+    pub enabled: bool,
+    /// Active force at full activation, optimal length and no shortening.
+    pub max_force: f32,
+    /// Time constant of activation rising towards excitation.
+    pub activation: f32,
+    /// Time constant of activation falling towards excitation.
+    pub deactivation: f32,
+    /// Length of peak active force, also the passive rest length.
+    pub optimal_length: f32,
+    /// Width of the force-length curve, relative to `optimal_length`.
+    pub width: f32,
+    /// Shortening speed at which the muscle can no longer pull.
+    pub max_velocity: f32,
+    /// Hill's curvature of the force-velocity curve, smaller is more curved.
+    pub curvature: f32,
+}
+
+impl Default for MuscleSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_force: 10.0,
+            activation: 0.5,
+            deactivation: 1.0,
+            optimal_length: 0.5,
+            width: 0.5,
+            max_velocity: 2.5,
+            curvature: 0.25,
+        }
+    }
+}
+
+impl MuscleSettings {
+    /// Active force pulling the ends together.
+    pub fn tension(&self, activation: f32, length: f32, velocity: f32) -> f32 {
+        self.max_force * activation * self.force_length(length) * self.force_velocity(velocity)
+    }
+
+    fn force_length(&self, length: f32) -> f32 {
+        let x = (length / self.optimal_length - 1.0) / self.width;
+        (-x * x).exp()
+    }
+
+    /// `velocity` is positive when lengthening. Flat past `max_velocity`
+    /// either way: 0 shortening, 1.8 lengthening.
+    fn force_velocity(&self, velocity: f32) -> f32 {
+        let v = (velocity / self.max_velocity).clamp(-1.0, 1.0);
+        if v < 0.0 {
+            (1.0 + v) / (1.0 - v / self.curvature)
+        } else {
+            1.8 - 0.8 * (1.0 - v) / (1.0 + v / self.curvature)
+        }
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Muscle {
+    /// Length the mapping commands as fully relaxed.
+    pub rest: f32,
+    /// Neural drive, between 0 and 1.
+    pub excitation: f32,
+    /// Fraction of the muscle contracting, lagging behind excitation.
+    pub activation: f32,
+}
+
+/// Applies a mapping's commanded length to a control spring: directly when it
+/// has no muscle, otherwise as excitation in proportion to how far below its
+/// rest length the command is, reaching 1 at half of it.
+pub fn drive(spring: &mut Spring, muscle: Option<Mut<Muscle>>, length: f32) {
+    match muscle {
+        Some(mut muscle) => {
+            muscle.excitation = ((muscle.rest - length) / (muscle.rest * 0.5)).clamp(0.0, 1.0);
+        }
+        None => spring.length = length,
+    }
+}
+
+/// Gives every control spring a muscle when they are enabled, resetting its
+/// rest length to the muscle's optimal length.
+fn attach_muscles(
+    mut commands: Commands,
+    settings: Res<WormSettings>,
+    mut springs: Query<(Entity, &mut Spring), (With<Control>, Without<Muscle>)>
+) {
+    let settings = &settings.muscle;
+    if !settings.enabled { return }
+    for (entity, mut spring) in springs.iter_mut() {
+        spring.length = settings.optimal_length;
+        commands.entity(entity).insert(Muscle { rest: settings.optimal_length, ..default() });
+    }
+}
+
+/// Moves activation towards excitation and pulls the ends of each muscle
+/// together with `max_force * activation * f(length) * f(velocity)`.
+pub fn muscle_force(
+    settings: Res<WormSettings>,
+    timestep: Res<Timestep>,
+    mut muscles: Query<(&Spring, &mut Muscle)>,
+    positions: Query<&Position>,
+    mut forces: Query<&mut Force>
+) {
+    let settings = &settings.muscle;
+    let dt = timestep.physics_dt();
+    for (spring, mut muscle) in muscles.iter_mut() {
+        let tau = if muscle.excitation > muscle.activation { settings.activation } else { settings.deactivation };
+        let rate = if tau > 0.0 { (dt / tau).min(1.0) } else { 1.0 };
+        muscle.activation += (muscle.excitation - muscle.activation) * rate;
+
+        let (Ok(a), Ok(b)) = (positions.get(spring.a), positions.get(spring.b)) else { continue };
+        let diff = a.now - b.now;
+        let length = diff.length();
+        if length == 0.0 { continue }
+        let u = diff / length;
+        let velocity = ((a.now - a.last) - (b.now - b.last)).dot(u) / dt;
+
+        let tension = settings.tension(muscle.activation, length, velocity);
+        forces.get_mut(spring.a).unwrap().0 -= u * tension;
+        forces.get_mut(spring.b).unwrap().0 += u * tension;
+    }
+}
+
+pub struct MusclePlugin;
+impl Plugin for MusclePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(SimulationStage, attach_muscles.before(Sensing));
+        app.add_system_to_stage(
+            SimulationStage,
+            muscle_force.after(Actuation).after(crate::physics::force_resetter)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn force_length_peaks_at_the_optimal_length() {
+        let muscle = MuscleSettings::default();
+        let stretch = muscle.optimal_length * muscle.width;
+        for (length, force) in [
+            (muscle.optimal_length, 1.0),
+            (muscle.optimal_length + stretch, (-1.0f32).exp()),
+            (muscle.optimal_length - stretch, (-1.0f32).exp()),
+            (0.0, (-1.0 / (muscle.width * muscle.width)).exp()),
+        ] {
+            assert!(close(muscle.force_length(length), force), "{} {}", length, force);
+        }
+    }
+
+    #[test]
+    fn force_velocity_stays_between_0_and_1_8() {
+        let muscle = MuscleSettings::default();
+        let max = muscle.max_velocity;
+        for (velocity, force) in [
+            (0.0, 1.0),
+            (-max, 0.0),
+            (-10.0 * max, 0.0),
+            (max, 1.8),
+            (10.0 * max, 1.8),
+            (-max / 2.0, 0.5 / (1.0 + 0.5 / muscle.curvature)),
+        ] {
+            assert!(close(muscle.force_velocity(velocity), force), "{} {}", velocity, force);
+        }
+    }
+
+    #[test]
+    fn tension_scales_with_activation() {
+        let muscle = MuscleSettings::default();
+        let (optimal, max) = (muscle.optimal_length, muscle.max_velocity);
+        for (activation, velocity, tension) in [
+            (1.0, 0.0, muscle.max_force),
+            (0.5, 0.0, muscle.max_force / 2.0),
+            (0.0, 0.0, 0.0),
+            (1.0, -max, 0.0),
+            (1.0, max, 1.8 * muscle.max_force),
+            (1.0, 2.0 * max, 1.8 * muscle.max_force),
+        ] {
+            assert!(close(muscle.tension(activation, optimal, velocity), tension), "{} {}", activation, velocity);
+        }
+    }
+}
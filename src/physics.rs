@@ -1,8 +1,20 @@
 use bevy::prelude::*;
 use bevy_inspector_egui::{Inspectable, RegisterInspectable};
+use serde::{Deserialize, Serialize};
 
-#[derive(Resource, Default)]
-struct DeltaTime(f32);
+use crate::{
+    collision::{collide_nodes, collide_obstacles},
+    config::{Config, Friction},
+    integrator::{integrate, Integrator},
+    medium::Medium,
+    step::{SimulationStage, Timestep},
+    worm::Actuation,
+};
+
+/// Label of the systems that move every `Position` forward one step,
+/// collisions included.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct Integration;
 
 #[derive(Component, Default, Inspectable)]
 pub struct Locked;
@@ -12,6 +24,15 @@ pub struct Mass(pub f32);
 pub struct Drag(pub f32);
 #[derive(Component, Default, Inspectable)]
 pub struct Force(pub Vec3);
+/// Resistive force theory coefficients of a spring, relative to the medium's.
+#[derive(Component, Default, Inspectable)]
+pub struct Rft {
+    pub tangential: f32,
+    pub normal: f32,
+}
+/// Collision radius of a node.
+#[derive(Component, Default, Inspectable)]
+pub struct Radius(pub f32);
 
 #[derive(Component, Default, Inspectable)]
 pub struct Position {
@@ -32,25 +53,99 @@ pub struct Spring {
     pub length: f32
 }
 
-fn force_resetter(mut forces: Query<&mut Force>) {
+/// How a spring's elastic force grows with its extension `x`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Nonlinearity {
+    /// `constant * x`
+    Linear,
+    /// `constant * x + coefficient * x³`
+    Cubic { coefficient: f32 },
+    /// `constant * (e^(rate * x) - 1) / rate`, stiffening under tension and
+    /// softening under compression for a positive rate.
+    Exponential { rate: f32 },
+}
+
+impl Default for Nonlinearity {
+    fn default() -> Self { Self::Linear }
+}
+
+/// Which way a spring pushes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SpringMode {
+    Both,
+    /// Slack when compressed, like a rope.
+    TensionOnly,
+    /// Slack when stretched, like a strut resting against its ends.
+    CompressionOnly,
+}
+
+impl Default for SpringMode {
+    fn default() -> Self { Self::Both }
+}
+
+/// Everything about a spring besides its constant and rest length.
+#[derive(Component, Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Material {
+    /// Force per unit of stretching speed, only while the spring is engaged.
+    pub damping: f32,
+    pub nonlinearity: Nonlinearity,
+    pub mode: SpringMode,
+    /// Extension relative to the rest length past which the spring breaks.
+    pub breaking_strain: Option<f32>,
+}
+
+impl Material {
+    /// Whether a one-sided spring pulls or pushes at all at extension `x`.
+    pub fn engaged(&self, x: f32) -> bool {
+        match self.mode {
+            SpringMode::Both => true,
+            SpringMode::TensionOnly => x > 0.0,
+            SpringMode::CompressionOnly => x < 0.0,
+        }
+    }
+
+    /// Force pulling the ends together for an extension `x` and a stretching
+    /// speed `rate`, negative when pushing them apart.
+    pub fn tension(&self, constant: f32, x: f32, rate: f32) -> f32 {
+        if !self.engaged(x) { return 0.0 }
+        let elastic = match self.nonlinearity {
+            Nonlinearity::Linear => constant * x,
+            Nonlinearity::Cubic { coefficient } => constant * x + coefficient * x * x * x,
+            Nonlinearity::Exponential { rate } if rate != 0.0 => constant * ((rate * x).exp() - 1.0) / rate,
+            Nonlinearity::Exponential { .. } => constant * x,
+        };
+        elastic + self.damping * rate
+    }
+}
+
+pub fn force_resetter(mut forces: Query<&mut Force>) {
     for mut force in forces.iter_mut() { force.0 = Vec3::ZERO; }
 }
 
 fn spring_mass_system(
-    springs: Query<&Spring>,
+    springs: Query<(&Spring, Option<&Material>)>,
     positions: Query<&Position>,
-    mut forces: Query<&mut Force>
+    mut forces: Query<&mut Force>,
+    timestep: Res<Timestep>
 ) {
-    for spring in springs.iter() {
-        let diff = {
+    let dt = timestep.physics_dt();
+    for (spring, material) in springs.iter() {
+        let (diff, v) = {
             let a = positions.get(spring.a).unwrap();
             let b = positions.get(spring.b).unwrap();
-            a.now - b.now
+            (a.now - b.now, (a.now - a.last) - (b.now - b.last))
         };
         let dist = diff.length();
 
-        let x = spring.length - dist;
-        let f = -spring.constant * x / dist;
+        let f = match material {
+            Some(material) => {
+                let rate = v.dot(diff) / dist / dt;
+                material.tension(spring.constant, dist - spring.length, rate) / dist
+            }
+            None => -spring.constant * (spring.length - dist) / dist,
+        };
 
         let mut force_a = forces.get_mut(spring.a).unwrap();
         force_a.0 -= diff * f;
@@ -60,10 +155,15 @@ fn spring_mass_system(
 }
 
 fn linear_drag_system(
+    config: Res<Config>,
+    medium: Res<Medium>,
+    timestep: Res<Timestep>,
     positions: Query<&Position>,
     springs: Query<(&Spring, &Drag)>,
     mut forces: Query<&mut Force>
 ) {
+    if config.friction != Friction::Drag { return }
+    let dt = timestep.physics_dt();
     for (spring, drag) in springs.iter() {
         let a = positions.get(spring.a).unwrap();
         let b = positions.get(spring.b).unwrap();
@@ -72,10 +172,11 @@ fn linear_drag_system(
         let normal = Vec3::new(tangent.y, -tangent.x, 0.0);
         let v_a = a.now - a.last;
         let v_b = b.now - b.last;
-        let v = (v_a + v_b) / 2.0;
+        let flow = medium.velocity((a.now + b.now) / 2.0) * dt;
+        let v = (v_a + v_b) / 2.0 - flow;
         let dot = Vec3::dot(v.normalize_or_zero(), normal.normalize_or_zero());
 
-        let force = dot * length * drag.0;
+        let force = dot * length * drag.0 * medium.viscosity;
         let mut force_a = forces.get_mut(spring.a).unwrap();
         force_a.0 -= normal * force;
         let mut force_b = forces.get_mut(spring.b).unwrap();
@@ -83,22 +184,80 @@ fn linear_drag_system(
     }
 }
 
-fn point_drag_system(mut query: Query<(&Position, &mut Force), With<Drag>>) {
-    let density = 1.0;
+fn point_drag_system(
+    config: Res<Config>,
+    medium: Res<Medium>,
+    timestep: Res<Timestep>,
+    mut query: Query<(&Position, &mut Force), With<Drag>>
+) {
+    if config.friction != Friction::Drag { return }
+    let dt = timestep.physics_dt();
     let area = 1.0;
     for (pos, mut force) in query.iter_mut() {
-        let v = pos.now - pos.last;
+        let v = pos.now - pos.last - medium.velocity(pos.now) * dt;
         let v_sq = v.length_squared();
-        let f = 2000.0 * density * area * v_sq;
+        let f = 2000.0 * medium.density * area * v_sq;
         force.0 -= v.normalize_or_zero() * f;
     }
 }
 
+/// Resists the velocity of each `Rft` spring's midpoint relative to the
+/// medium separately along and across the spring, in proportion to its
+/// length and the medium's viscosity, split between both ends.
+fn rft_system(
+    config: Res<Config>,
+    medium: Res<Medium>,
+    timestep: Res<Timestep>,
+    positions: Query<&Position>,
+    springs: Query<(&Spring, &Rft)>,
+    mut forces: Query<&mut Force>
+) {
+    if config.friction != Friction::Rft { return }
+    let dt = timestep.physics_dt();
+    let tangential = config.rft.tangential * medium.viscosity;
+    let normal = config.rft.normal * medium.viscosity;
+    for (spring, rft) in springs.iter() {
+        let a = positions.get(spring.a).unwrap();
+        let b = positions.get(spring.b).unwrap();
+        let axis = b.now - a.now;
+        let length = axis.length();
+        let tangent = axis.normalize_or_zero();
+        let flow = medium.velocity((a.now + b.now) / 2.0);
+        let v = ((a.now - a.last) + (b.now - b.last)) / 2.0 / dt - flow;
+        let v_t = tangent * v.dot(tangent);
+        let v_n = v - v_t;
+
+        let force = -(v_t * tangential * rft.tangential + v_n * normal * rft.normal) * length;
+        let mut force_a = forces.get_mut(spring.a).unwrap();
+        force_a.0 += force / 2.0;
+        let mut force_b = forces.get_mut(spring.b).unwrap();
+        force_b.0 += force / 2.0;
+    }
+}
+
+/// Despawns every spring stretched or compressed past its breaking strain.
+fn break_springs(
+    mut commands: Commands,
+    springs: Query<(Entity, &Spring, &Material)>,
+    positions: Query<&Position>
+) {
+    for (entity, spring, material) in springs.iter() {
+        let Some(limit) = material.breaking_strain else { continue };
+        let (Ok(a), Ok(b)) = (positions.get(spring.a), positions.get(spring.b)) else { continue };
+        let strain = ((a.now - b.now).length() - spring.length) / spring.length;
+        if strain.abs() > limit {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
 fn verlet_integration(
+    config: Res<Config>,
     mut query: Query<(&mut Position, &Mass, &Force), Without<Locked>>,
-    dt: Res<DeltaTime>
+    timestep: Res<Timestep>
 ) {
-    let dt = dt.0;
+    if config.integrator != Integrator::Verlet { return }
+    let dt = timestep.physics_dt();
     for (mut pos, mass, force) in query.iter_mut() {
         let last = pos.now;
         let a = force.0 / mass.0 * dt * dt;
@@ -111,21 +270,133 @@ fn verlet_integration(
 pub struct PhysicsPlugin;
 impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(DeltaTime(0.05));
         app.register_inspectable::<Mass>();
         app.register_inspectable::<Drag>();
         app.register_inspectable::<Force>();
+        app.register_inspectable::<Radius>();
+        app.register_inspectable::<Rft>();
         app.register_inspectable::<Position>();
         app.register_inspectable::<Spring>();
-        app.add_system(force_resetter);
-        app.add_system(spring_mass_system.after(force_resetter));
-        app.add_system(point_drag_system.after(force_resetter));
-        app.add_system(linear_drag_system.after(force_resetter));
-        app.add_system(
+        app.add_system_to_stage(SimulationStage, force_resetter.after(Actuation));
+        app.add_system_to_stage(SimulationStage, spring_mass_system.after(force_resetter));
+        app.add_system_to_stage(SimulationStage, point_drag_system.after(force_resetter));
+        app.add_system_to_stage(SimulationStage, linear_drag_system.after(force_resetter));
+        app.add_system_to_stage(SimulationStage, rft_system.after(force_resetter));
+        app.add_system_to_stage(
+            SimulationStage,
             verlet_integration
+                .label(Integration)
                 .after(spring_mass_system)
                 .after(point_drag_system)
                 .after(linear_drag_system)
+                .after(rft_system)
+                .after(crate::muscle::muscle_force)
+                .after(crate::pressure::pressure_system)
         );
+        app.add_system_to_stage(
+            SimulationStage,
+            integrate
+                .label(Integration)
+                .after(spring_mass_system)
+                .after(point_drag_system)
+                .after(linear_drag_system)
+                .after(rft_system)
+                .after(crate::muscle::muscle_force)
+                .after(crate::pressure::pressure_system)
+        );
+        app.add_system_to_stage(
+            SimulationStage,
+            collide_nodes.label(Integration).after(verlet_integration).after(integrate)
+        );
+        app.add_system_to_stage(
+            SimulationStage,
+            collide_obstacles.label(Integration).after(collide_nodes)
+        );
+        app.add_system_to_stage(SimulationStage, break_springs.after(Integration));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_sided_springs_go_slack() {
+        let rope = Material { mode: SpringMode::TensionOnly, ..default() };
+        let strut = Material { mode: SpringMode::CompressionOnly, ..default() };
+        assert_eq!(rope.tension(2.0, 0.5, 0.0), 1.0);
+        assert_eq!(rope.tension(2.0, -0.5, 0.0), 0.0);
+        assert_eq!(strut.tension(2.0, 0.5, 0.0), 0.0);
+        assert_eq!(strut.tension(2.0, -0.5, 0.0), -1.0);
+    }
+
+    #[test]
+    fn damping_only_acts_while_engaged() {
+        let rope = Material { damping: 3.0, mode: SpringMode::TensionOnly, ..default() };
+        assert_eq!(rope.tension(2.0, 0.5, 1.0), 4.0);
+        assert_eq!(rope.tension(2.0, -0.5, 1.0), 0.0);
+    }
+
+    #[test]
+    fn nonlinear_springs_stiffen() {
+        let cubic = Material { nonlinearity: Nonlinearity::Cubic { coefficient: 1.0 }, ..default() };
+        assert_eq!(cubic.tension(2.0, 2.0, 0.0), 12.0);
+        let exponential = Material { nonlinearity: Nonlinearity::Exponential { rate: 1.0 }, ..default() };
+        assert!(exponential.tension(1.0, 1.0, 0.0) > 1.0);
+        assert!(exponential.tension(1.0, -1.0, 0.0) > -1.0);
+        let flat = Material { nonlinearity: Nonlinearity::Exponential { rate: 0.0 }, ..default() };
+        assert_eq!(flat.tension(2.0, 0.5, 0.0), 1.0);
+    }
+
+    #[test]
+    fn overstretched_springs_break() {
+        let mut world = World::new();
+        let a = world.spawn(Position::new(Vec3::ZERO)).id();
+        let b = world.spawn(Position::new(Vec3::new(1.4, 0.0, 0.0))).id();
+        let material = |limit| Material { breaking_strain: Some(limit), ..default() };
+        let breaks = world.spawn((Spring { a, b, constant: 1.0, length: 1.0 }, material(0.3))).id();
+        let holds = world.spawn((Spring { a, b, constant: 1.0, length: 1.0 }, material(0.5))).id();
+        let unbreakable = world.spawn((Spring { a, b, constant: 1.0, length: 1.0 }, Material::default())).id();
+
+        SystemStage::single_threaded().with_system(break_springs).run(&mut world);
+
+        assert!(world.get_entity(breaks).is_none());
+        assert!(world.get_entity(holds).is_some());
+        assert!(world.get_entity(unbreakable).is_some());
+    }
+
+    /// Two unit masses on a unit spring under `verlet_integration`, released
+    /// 0.1 stretched: `ω = √2`. Returns the largest extension seen.
+    fn verlet_two_nodes(dt: f32, steps: usize) -> f32 {
+        let mut world = World::new();
+        world.insert_resource(Config::default());
+        world.insert_resource(Timestep { physics_dt: dt, substeps: 1, ..default() });
+        let node = |world: &mut World, x: f32| {
+            world.spawn((Position::new(Vec3::new(x, 0.0, 0.0)), Mass(1.0), Force::default())).id()
+        };
+        let a = node(&mut world, 0.0);
+        let b = node(&mut world, 1.1);
+        world.spawn((Spring { a, b, constant: 1.0, length: 1.0 }, Material::default()));
+        let mut reset = SystemStage::single_threaded().with_system(force_resetter);
+        let mut springs = SystemStage::single_threaded().with_system(spring_mass_system);
+        let mut integrate = SystemStage::single_threaded().with_system(verlet_integration);
+        let mut largest = 0.0_f32;
+        for _ in 0..steps {
+            reset.run(&mut world);
+            springs.run(&mut world);
+            integrate.run(&mut world);
+            let [a, b] = [a, b].map(|node| world.get::<Position>(node).unwrap().now);
+            let extension = (b - a).length() - 1.0;
+            if !extension.is_finite() { return f32::NAN }
+            largest = largest.max(extension.abs());
+        }
+        largest
+    }
+
+    #[test]
+    fn verlet_is_stable_up_to_two_over_omega() {
+        assert!(verlet_two_nodes(0.01, 1000) < 0.1 + 1e-3);
+        assert!(verlet_two_nodes(0.9 * std::f32::consts::SQRT_2, 200) < 1.0);
+        assert!(!(verlet_two_nodes(1.1 * std::f32::consts::SQRT_2, 200) < 1e3));
     }
 }
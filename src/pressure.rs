@@ -0,0 +1,105 @@
+use bevy::prelude::*;
+
+use crate::{
+    physics::{force_resetter, Force, Position},
+    step::SimulationStage,
+};
+
+/// Gas inside a closed ring of nodes, pushing each edge outwards with
+/// `stiffness * (area / current_area - 1)` per unit of length so the ring
+/// keeps its rest `area`.
+#[derive(Component, Debug, Clone)]
+pub struct Pressure {
+    /// The ring, in order around it.
+    pub nodes: Vec<Entity>,
+    pub area: f32,
+    pub stiffness: f32,
+}
+
+/// Shoelace area, positive when `points` run counter-clockwise.
+pub fn signed_area(points: &[Vec3]) -> f32 {
+    points.iter()
+        .zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| a.x * b.y - b.x * a.y)
+        .sum::<f32>() * 0.5
+}
+
+pub fn pressure_system(
+    rings: Query<&Pressure>,
+    positions: Query<&Position>,
+    mut forces: Query<&mut Force>
+) {
+    for ring in rings.iter() {
+        let Ok(points) = ring.nodes.iter()
+            .map(|node| positions.get(*node).map(|pos| pos.now))
+            .collect::<Result<Vec<_>, _>>() else { continue };
+        let area = signed_area(&points);
+        if area.abs() < f32::EPSILON { continue }
+        // Taking the area's sign also turns the normals outwards for clockwise
+        // rings.
+        let pressure = ring.stiffness * (ring.area / area.abs() - 1.0) * area.signum();
+        for (i, (a, b)) in points.iter().zip(points.iter().cycle().skip(1)).enumerate() {
+            let edge = *b - *a;
+            // Outward normal of a counter-clockwise edge, scaled by its length.
+            let push = Vec3::new(edge.y, -edge.x, 0.0) * pressure * 0.5;
+            forces.get_mut(ring.nodes[i]).unwrap().0 += push;
+            forces.get_mut(ring.nodes[(i + 1) % ring.nodes.len()]).unwrap().0 += push;
+        }
+    }
+}
+
+pub struct PressurePlugin;
+impl Plugin for PressurePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(SimulationStage, pressure_system.after(force_resetter));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: [Vec3; 4] = [
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(1.0, 0.0, 0.0),
+        Vec3::new(1.0, 1.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+    ];
+
+    #[test]
+    fn area_is_positive_counter_clockwise() {
+        assert_eq!(signed_area(&SQUARE), 1.0);
+        let mut clockwise = SQUARE;
+        clockwise.reverse();
+        assert_eq!(signed_area(&clockwise), -1.0);
+    }
+
+    /// Force on every node of a unit square held at `area`, dotted with the
+    /// direction out of the square.
+    fn outward_forces(points: &[Vec3], area: f32) -> Vec<f32> {
+        let mut world = World::new();
+        let nodes: Vec<Entity> = points.iter()
+            .map(|point| world.spawn((Position::new(*point), Force::default())).id())
+            .collect();
+        world.spawn(Pressure { nodes: nodes.clone(), area, stiffness: 1.0 });
+
+        SystemStage::single_threaded().with_system(pressure_system).run(&mut world);
+
+        let center = Vec3::new(0.5, 0.5, 0.0);
+        nodes.iter()
+            .zip(points)
+            .map(|(node, point)| world.get::<Force>(*node).unwrap().0.dot(*point - center))
+            .collect()
+    }
+
+    #[test]
+    fn squashed_rings_push_out_either_way_round() {
+        let mut clockwise = SQUARE;
+        clockwise.reverse();
+        for points in [SQUARE, clockwise] {
+            assert!(outward_forces(&points, 2.0).iter().all(|force| *force > 0.0));
+            assert!(outward_forces(&points, 0.5).iter().all(|force| *force < 0.0));
+            assert!(outward_forces(&points, 1.0).iter().all(|force| force.abs() < 1e-6));
+        }
+    }
+}
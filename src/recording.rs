@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    str::FromStr,
+};
+
+use bevy::{app::AppExit, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    brain::CTRNN,
+    config::Config,
+    physics::{Position, Spring},
+    step::SimulationStage,
+    worm::{centers_of_mass, WormId},
+    InitialPosition, TimeTracker, TimeTracker2,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    Com,
+    Nodes,
+    Neurons,
+    Springs,
+    Fitness,
+}
+
+impl Channel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Com => "com",
+            Self::Nodes => "nodes",
+            Self::Neurons => "neurons",
+            Self::Springs => "springs",
+            Self::Fitness => "fitness",
+        }
+    }
+
+    fn header(&self) -> &'static str {
+        match self {
+            Self::Com => "time,worm,x,y,displacement",
+            Self::Nodes => "time,worm,node,x,y",
+            Self::Neurons => "time,worm,neuron,output",
+            Self::Springs => "time,worm,spring,a,b,rest_length,length",
+            Self::Fitness => "time,worm,neuron,fitness,average",
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "com" => Ok(Self::Com),
+            "nodes" => Ok(Self::Nodes),
+            "neurons" => Ok(Self::Neurons),
+            "springs" => Ok(Self::Springs),
+            "fitness" => Ok(Self::Fitness),
+            _ => Err(format!("unknown channel `{}`", s)),
+        }
+    }
+}
+
+/// Writes each enabled channel to `<output_dir>/<channel>.csv`. Without an
+/// output directory only the center of mass is recorded, to stdout, in the
+/// headerless `time,displacement` format the experiment scripts read, or
+/// `time,worm,displacement` when there are several worms.
+#[derive(Resource)]
+pub struct Recorder {
+    writers: HashMap<Channel, Box<dyn Write + Send + Sync>>,
+    stdout: bool,
+    several: bool,
+    sample: Option<f32>,
+}
+
+impl Recorder {
+    pub fn open(config: &Config) -> io::Result<Self> {
+        let mut writers: HashMap<Channel, Box<dyn Write + Send + Sync>> = HashMap::new();
+        let seed = config.seed.map(|seed| format!("# seed: {}", seed));
+        let stdout = config.output_dir.is_none();
+        match &config.output_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir)?;
+                for channel in &config.channels {
+                    let path = dir.join(channel.name()).with_extension("csv");
+                    let mut writer = BufWriter::new(File::create(path)?);
+                    if let Some(seed) = &seed { writeln!(writer, "{}", seed)?; }
+                    writeln!(writer, "{}", channel.header())?;
+                    writers.insert(*channel, Box::new(writer));
+                }
+            }
+            None => {
+                if let Some(seed) = &seed { println!("{}", seed); }
+                if config.log_every_frame {
+                    writers.insert(Channel::Com, Box::new(io::stdout()));
+                }
+            }
+        }
+        Ok(Self { writers, stdout, several: config.worms().len() > 1, sample: None })
+    }
+
+    pub fn enabled(&self, channel: Channel) -> bool {
+        self.writers.contains_key(&channel)
+    }
+
+    /// Appends a line to `channel`, which stops recording if that fails.
+    pub fn write(&mut self, channel: Channel, line: fmt::Arguments) {
+        if let Some(writer) = self.writers.get_mut(&channel) {
+            if let Err(err) = writeln!(writer, "{}", line) {
+                eprintln!("could not record {}, stopping it: {}", channel.name(), err);
+                self.writers.remove(&channel);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        for (channel, writer) in self.writers.iter_mut() {
+            if let Err(err) = writer.flush() {
+                eprintln!("could not record {}: {}", channel.name(), err);
+            }
+        }
+    }
+}
+
+fn sample_clock(
+    time: Res<TimeTracker>,
+    mut time_t: ResMut<TimeTracker2>,
+    config: Res<Config>,
+    mut recorder: ResMut<Recorder>
+) {
+    let t = (time.0 * config.record_rate).floor() / config.record_rate;
+    recorder.sample = if t > time_t.0 {
+        time_t.0 = t;
+        Some(t)
+    } else { None };
+}
+
+fn record_com(
+    mut recorder: ResMut<Recorder>,
+    worms: Query<(Entity, &WormId, &InitialPosition)>,
+    nodes: Query<(&Parent, &Position)>
+) {
+    let Some(t) = recorder.sample else { return };
+    if !recorder.enabled(Channel::Com) { return }
+
+    let centers = centers_of_mass(nodes.iter());
+    let mut worms: Vec<_> = worms.iter().collect();
+    worms.sort_by_key(|(_, id, _)| **id);
+    for (entity, id, initial) in worms {
+        let (Some(total), Some(initial)) = (centers.get(&entity), initial.0) else { continue };
+        let diff = *total - initial;
+        let displacement = diff.x.hypot(diff.y);
+        if !recorder.stdout {
+            recorder.write(Channel::Com, format_args!("{},{},{},{},{}", t, id.0, total.x, total.y, displacement));
+        } else if recorder.several {
+            recorder.write(Channel::Com, format_args!("{},{},{}", t, id.0, displacement));
+        } else {
+            recorder.write(Channel::Com, format_args!("{},{}", t, displacement));
+        }
+    }
+}
+
+fn record_nodes(
+    mut recorder: ResMut<Recorder>,
+    worms: Query<&WormId>,
+    positions: Query<(Entity, &Parent, &Position)>
+) {
+    let Some(t) = recorder.sample else { return };
+    if !recorder.enabled(Channel::Nodes) { return }
+    for (entity, parent, pos) in positions.iter() {
+        let Ok(id) = worms.get(parent.get()) else { continue };
+        recorder.write(Channel::Nodes, format_args!("{},{},{},{},{}", t, id.0, entity.index(), pos.now.x, pos.now.y));
+    }
+}
+
+fn record_neurons(mut recorder: ResMut<Recorder>, ctrnns: Query<(&WormId, &CTRNN)>) {
+    let Some(t) = recorder.sample else { return };
+    if !recorder.enabled(Channel::Neurons) { return }
+    for (id, ctrnn) in ctrnns.iter() {
+        for (neuron, output) in ctrnn.get_outputs().iter().enumerate() {
+            recorder.write(Channel::Neurons, format_args!("{},{},{},{}", t, id.0, neuron, output));
+        }
+    }
+}
+
+fn record_springs(
+    mut recorder: ResMut<Recorder>,
+    worms: Query<&WormId>,
+    springs: Query<(Entity, &Parent, &Spring)>,
+    positions: Query<&Position>
+) {
+    let Some(t) = recorder.sample else { return };
+    if !recorder.enabled(Channel::Springs) { return }
+    for (entity, parent, spring) in springs.iter() {
+        let Ok(id) = worms.get(parent.get()) else { continue };
+        let (Ok(a), Ok(b)) = (positions.get(spring.a), positions.get(spring.b)) else { continue };
+        let length = (a.now - b.now).length();
+        recorder.write(Channel::Springs, format_args!(
+            "{},{},{},{},{},{},{}",
+            t, id.0, entity.index(), spring.a.index(), spring.b.index(), spring.length, length
+        ));
+    }
+}
+
+fn record_fitness(mut recorder: ResMut<Recorder>, config: Res<Config>, ctrnns: Query<(&WormId, &CTRNN)>) {
+    let Some(t) = recorder.sample else { return };
+    if !recorder.enabled(Channel::Fitness) { return }
+    let history_length = config.history_length as f64;
+    for (id, ctrnn) in ctrnns.iter() {
+        for (neuron, (sum, avg_sum)) in ctrnn.fitness_sum.iter().zip(&ctrnn.avg_fitness_sum).enumerate() {
+            recorder.write(Channel::Fitness, format_args!(
+                "{},{},{},{},{}",
+                t, id.0, neuron, sum / history_length, avg_sum / history_length
+            ));
+        }
+    }
+}
+
+fn flush_on_exit(mut exit: EventReader<AppExit>, mut recorder: ResMut<Recorder>) {
+    if exit.iter().next().is_some() {
+        recorder.flush();
+    }
+}
+
+pub struct RecordingPlugin;
+impl Plugin for RecordingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(SimulationStage, sample_clock.after(crate::increment_time));
+        app.add_system_to_stage(SimulationStage, record_com.after(sample_clock));
+        app.add_system_to_stage(SimulationStage, record_nodes.after(sample_clock));
+        app.add_system_to_stage(SimulationStage, record_neurons.after(sample_clock));
+        app.add_system_to_stage(SimulationStage, record_springs.after(sample_clock));
+        app.add_system_to_stage(SimulationStage, record_fitness.after(sample_clock));
+        app.add_system_to_stage(CoreStage::Last, flush_on_exit);
+    }
+}
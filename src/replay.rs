@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use bevy::{app::AppExit, prelude::*};
+use bevy_egui::{egui, EguiContext, EguiPlugin};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    brain::CTRNN,
+    physics::{Position, Spring},
+    scene::{Obstacle, Obstacles},
+    step::SimulationStage,
+    worm::{Control, CyclicalMapping, Neurons, SpringHidden},
+    TimeTracker,
+};
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    tick: f32,
+    seed: Option<u64>,
+    obstacles: Vec<Obstacle>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SpringRecord {
+    a: u32,
+    b: u32,
+    control: Option<(i32, f32)>,
+    hidden: bool,
+}
+
+/// Which nodes exist and how springs connect them. Only written when it
+/// differs from the previous frame, e.g. after a segment has grown.
+#[derive(Serialize, Deserialize, Clone)]
+struct Topology {
+    nodes: u32,
+    springs: Vec<SpringRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Frame {
+    time: f32,
+    topology: Option<Topology>,
+    positions: Vec<[f32; 2]>,
+    lengths: Vec<f32>,
+    voltages: Vec<Vec<f64>>,
+}
+
+/// Replay file being written, until writing to it fails.
+#[derive(Resource)]
+pub struct ReplayWriter {
+    writer: Option<BufWriter<File>>,
+    nodes: Vec<Entity>,
+    springs: Vec<Entity>,
+}
+
+impl ReplayWriter {
+    pub fn create(
+        path: impl AsRef<Path>,
+        tick: f32,
+        seed: Option<u64>,
+        obstacles: Vec<Obstacle>
+    ) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(&mut writer, &Header { tick, seed, obstacles })
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(Self { writer: Some(writer), nodes: vec![], springs: vec![] })
+    }
+}
+
+fn record_frame(
+    mut replay: ResMut<ReplayWriter>,
+    time: Res<TimeTracker>,
+    positions: Query<(Entity, &Position)>,
+    springs: Query<(Entity, &Spring, Option<&Control>, Option<&SpringHidden>)>,
+    ctrnns: Query<(Entity, &CTRNN)>
+) {
+    if replay.writer.is_none() { return }
+    let mut nodes: Vec<(Entity, &Position)> = positions.iter().collect();
+    nodes.sort_by_key(|(entity, _)| entity.to_bits());
+    let mut edges: Vec<_> = springs.iter().collect();
+    edges.sort_by_key(|(entity, ..)| entity.to_bits());
+    let mut brains: Vec<(Entity, &CTRNN)> = ctrnns.iter().collect();
+    brains.sort_by_key(|(entity, _)| entity.to_bits());
+
+    let node_ids: Vec<Entity> = nodes.iter().map(|(entity, _)| *entity).collect();
+    let spring_ids: Vec<Entity> = edges.iter().map(|(entity, ..)| *entity).collect();
+    let topology = if node_ids != replay.nodes || spring_ids != replay.springs {
+        let index: HashMap<Entity, u32> = node_ids.iter()
+            .enumerate()
+            .map(|(i, entity)| (*entity, i as u32))
+            .collect();
+        let springs = edges.iter()
+            .map(|(_, spring, control, hidden)| SpringRecord {
+                a: index[&spring.a],
+                b: index[&spring.b],
+                control: control.map(|c| (c.index, c.side)),
+                hidden: hidden.is_some(),
+            })
+            .collect();
+        replay.nodes = node_ids;
+        replay.springs = spring_ids;
+        Some(Topology { nodes: nodes.len() as u32, springs })
+    } else { None };
+
+    let frame = Frame {
+        time: time.0,
+        topology,
+        positions: nodes.iter().map(|(_, pos)| [pos.now.x, pos.now.y]).collect(),
+        lengths: edges.iter().map(|(_, spring, ..)| spring.length).collect(),
+        voltages: brains.iter().map(|(_, ctrnn)| ctrnn.voltages.clone()).collect(),
+    };
+    let Some(writer) = &mut replay.writer else { return };
+    if let Err(err) = bincode::serialize_into(writer, &frame) {
+        eprintln!("could not write the replay, stopping it: {}", err);
+        replay.writer = None;
+    }
+}
+
+fn flush_on_exit(mut exit: EventReader<AppExit>, mut replay: ResMut<ReplayWriter>) {
+    if exit.iter().next().is_none() { return }
+    if let Some(writer) = &mut replay.writer {
+        if let Err(err) = writer.flush() {
+            eprintln!("could not write the replay: {}", err);
+        }
+    }
+}
+
+/// Writes every tick of the running simulation to a `ReplayWriter`.
+pub struct ReplayRecorderPlugin;
+impl Plugin for ReplayRecorderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(SimulationStage, record_frame.after(crate::increment_time));
+        app.add_system_to_stage(CoreStage::Last, flush_on_exit);
+    }
+}
+
+#[derive(Resource)]
+struct Playback {
+    tick: f32,
+    frames: Vec<Frame>,
+    /// For every frame, the index of the frame holding its topology.
+    topology_of: Vec<usize>,
+    cursor: f32,
+    speed: f32,
+    paused: bool,
+    body: Option<Body>,
+}
+
+struct Body {
+    parent: Entity,
+    topology: usize,
+    nodes: Vec<Entity>,
+    springs: Vec<Entity>,
+}
+
+fn load(path: impl AsRef<Path>) -> Result<(Playback, Obstacles), String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("could not open {}: {}", path.display(), e))?;
+    let mut reader = BufReader::new(file);
+    let header: Header = bincode::deserialize_from(&mut reader)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+
+    let mut frames = vec![];
+    let mut topology_of = vec![];
+    loop {
+        let frame: Frame = match bincode::deserialize_from(&mut reader) {
+            Ok(frame) => frame,
+            Err(err) => match *err {
+                bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                _ => return Err(format!("could not read {}: {}", path.display(), err)),
+            },
+        };
+        let topology = match frame.topology {
+            Some(_) => frames.len(),
+            None => *topology_of.last().ok_or("replay starts without a topology")?,
+        };
+        topology_of.push(topology);
+        frames.push(frame);
+    }
+    if frames.is_empty() {
+        return Err(format!("{} holds no frames", path.display()));
+    }
+
+    let playback = Playback {
+        tick: header.tick,
+        frames,
+        topology_of,
+        cursor: 0.0,
+        speed: 1.0,
+        paused: false,
+        body: None,
+    };
+    Ok((playback, Obstacles(header.obstacles)))
+}
+
+impl Playback {
+    fn index(&self) -> usize {
+        (self.cursor.max(0.0) as usize).min(self.frames.len() - 1)
+    }
+
+    fn last(&self) -> f32 {
+        (self.frames.len() - 1) as f32
+    }
+}
+
+fn advance_playback(time: Res<Time>, mut playback: ResMut<Playback>) {
+    if playback.paused { return }
+    let frames = time.delta_seconds() * playback.speed / playback.tick;
+    playback.cursor = (playback.cursor + frames).min(playback.last());
+}
+
+fn playback_keys(keys: Res<Input<KeyCode>>, mut playback: ResMut<Playback>) {
+    if keys.just_pressed(KeyCode::Space) {
+        playback.paused = !playback.paused;
+    }
+    if keys.just_pressed(KeyCode::Right) {
+        playback.cursor = (playback.cursor.floor() + 1.0).min(playback.last());
+    }
+    if keys.just_pressed(KeyCode::Left) {
+        playback.cursor = (playback.cursor.floor() - 1.0).max(0.0);
+    }
+    if keys.just_pressed(KeyCode::Up) {
+        playback.speed *= 2.0;
+    }
+    if keys.just_pressed(KeyCode::Down) {
+        playback.speed /= 2.0;
+    }
+}
+
+fn show_frame(
+    mut commands: Commands,
+    mut playback: ResMut<Playback>,
+    mut positions: Query<&mut Position>,
+    mut springs: Query<&mut Spring>
+) {
+    let playback = &mut *playback;
+    let index = playback.index();
+    let frame = &playback.frames[index];
+    let topology = playback.topology_of[index];
+
+    if playback.body.as_ref().map(|body| body.topology) != Some(topology) {
+        if let Some(body) = playback.body.take() {
+            commands.entity(body.parent).despawn_recursive();
+        }
+        let layout = playback.frames[topology].topology.as_ref().unwrap();
+        let mut nodes = vec![];
+        let mut edges = vec![];
+        let parent = commands.spawn((
+            Transform::default(),
+            GlobalTransform::default(),
+            VisibilityBundle::default(),
+            CyclicalMapping,
+            Neurons(vec![0.0; frame.voltages.first().map_or(1, Vec::len).max(1)])
+        )).with_children(|parent| {
+            for pos in &frame.positions {
+                nodes.push(parent.spawn(Position::new(Vec3::new(pos[0], pos[1], 0.0))).id());
+            }
+            for (record, length) in layout.springs.iter().zip(&frame.lengths) {
+                let mut spring = parent.spawn(Spring {
+                    a: nodes[record.a as usize],
+                    b: nodes[record.b as usize],
+                    constant: 0.0,
+                    length: *length,
+                });
+                if let Some((index, side)) = record.control {
+                    spring.insert(Control { index, side });
+                }
+                if record.hidden {
+                    spring.insert(SpringHidden);
+                }
+                edges.push(spring.id());
+            }
+        }).id();
+        playback.body = Some(Body { parent, topology, nodes, springs: edges });
+        return;
+    }
+
+    let body = playback.body.as_ref().unwrap();
+    for (entity, pos) in body.nodes.iter().zip(&frame.positions) {
+        if let Ok(mut position) = positions.get_mut(*entity) {
+            *position = Position::new(Vec3::new(pos[0], pos[1], 0.0));
+        }
+    }
+    for (entity, length) in body.springs.iter().zip(&frame.lengths) {
+        if let Ok(mut spring) = springs.get_mut(*entity) {
+            spring.length = *length;
+        }
+    }
+}
+
+fn replay_window(mut egui_context: ResMut<EguiContext>, mut playback: ResMut<Playback>) {
+    let playback = &mut *playback;
+    let last = playback.last();
+    let frame = &playback.frames[playback.index()];
+    let time = frame.time;
+    let voltages: Vec<String> = frame.voltages.iter().map(|v| format!("{:.3?}", v)).collect();
+    egui::Window::new("Replay")
+        .default_size(egui::Vec2::new(300.0, 150.0))
+        .show(egui_context.ctx_mut(), |ui| {
+            ui.label(format!("t = {:.2} s", time));
+            ui.add(egui::Slider::new(&mut playback.cursor, 0.0..=last).text("frame"));
+            ui.add(egui::Slider::new(&mut playback.speed, 0.125..=16.0).logarithmic(true).text("speed"));
+            ui.checkbox(&mut playback.paused, "paused");
+            for voltages in &voltages {
+                ui.label(voltages);
+            }
+        });
+}
+
+pub struct ReplayPlugin;
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(EguiPlugin);
+        app.add_system(playback_keys);
+        app.add_system(replay_window.after(playback_keys));
+        app.add_system(advance_playback.after(replay_window));
+        app.add_system(show_frame.after(advance_playback));
+    }
+}
+
+/// `blob replay <file>`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let path = args.first().ok_or("replay expects a file")?;
+    let (playback, obstacles) = load(path)?;
+
+    let mut app = App::new();
+    crate::add_rendering(&mut app);
+    app
+        .insert_resource(playback)
+        .insert_resource(obstacles)
+        .add_plugin(ReplayPlugin)
+        .run();
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use std::{f32::consts::TAU, fs, path::Path};
+
+use bevy::prelude::*;
+use bevy_prototype_debug_lines::DebugLines;
+use serde::{Deserialize, Serialize};
+
+/// Segments drawn per circle.
+const CIRCLE_RESOLUTION: usize = 32;
+
+/// Written in a scene file as e.g. `circle = { center = [2.0, 0.0], radius = 0.5 }`
+/// inside an `[[obstacles]]` table.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Obstacle {
+    Segment { a: [f32; 2], b: [f32; 2] },
+    Circle { center: [f32; 2], radius: f32 },
+    /// Closed polygon, nodes are kept out of its inside as well as its edges.
+    Polygon { points: Vec<[f32; 2]> },
+}
+
+fn closest_on_segment(point: Vec2, a: Vec2, b: Vec2) -> Vec2 {
+    let ab = b - a;
+    let t = ((point - a).dot(ab) / ab.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+    a + ab * t
+}
+
+/// Even-odd rule.
+fn contains(points: &[Vec2], point: Vec2) -> bool {
+    let mut inside = false;
+    for (a, b) in points.iter().zip(points.iter().cycle().skip(1)) {
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Unit normal of the line through `a` and `b`, on its left.
+fn normal(a: Vec2, b: Vec2) -> Vec2 {
+    (b - a).perp().try_normalize().unwrap_or(Vec2::X)
+}
+
+/// Pushes a node of `radius` at `point` clear of `closest`, along `normal`
+/// when it sits exactly on it.
+fn away_from(point: Vec2, closest: Vec2, radius: f32, normal: Vec2) -> Option<Vec2> {
+    let diff = point - closest;
+    let dist = diff.length();
+    if dist >= radius { return None }
+    let direction = if dist > 0.0 { diff / dist } else { normal };
+    Some(direction * (radius - dist))
+}
+
+impl Obstacle {
+    /// How far a node of `radius` at `point` has to move to stop overlapping
+    /// the obstacle, if it does.
+    pub fn push_out(&self, point: Vec2, radius: f32) -> Option<Vec2> {
+        match self {
+            Self::Segment { a, b } => {
+                let (a, b) = (Vec2::from_array(*a), Vec2::from_array(*b));
+                away_from(point, closest_on_segment(point, a, b), radius, normal(a, b))
+            }
+            Self::Circle { center, radius: size } => {
+                let diff = point - Vec2::from_array(*center);
+                let dist = diff.length();
+                if dist >= size + radius { return None }
+                let normal = if dist > 0.0 { diff / dist } else { Vec2::X };
+                Some(normal * (size + radius - dist))
+            }
+            Self::Polygon { points } => {
+                let points: Vec<Vec2> = points.iter().copied().map(Vec2::from_array).collect();
+                let (closest, a, b) = points.iter()
+                    .zip(points.iter().cycle().skip(1))
+                    .map(|(a, b)| (closest_on_segment(point, *a, *b), *a, *b))
+                    .min_by(|x, y| x.0.distance_squared(point).total_cmp(&y.0.distance_squared(point)))?;
+                // The closest edge's normal facing away from the inside.
+                let center = points.iter().copied().sum::<Vec2>() / points.len() as f32;
+                let normal = normal(a, b);
+                let normal = if normal.dot(closest - center) < 0.0 { -normal } else { normal };
+                if contains(&points, point) {
+                    let out = closest - point;
+                    Some(out + out.try_normalize().unwrap_or(normal) * radius)
+                } else {
+                    away_from(point, closest, radius, normal)
+                }
+            }
+        }
+    }
+
+    /// The obstacle's outline as line segments.
+    fn outline(&self) -> Vec<(Vec2, Vec2)> {
+        match self {
+            Self::Segment { a, b } => vec![(Vec2::from_array(*a), Vec2::from_array(*b))],
+            Self::Circle { center, radius } => {
+                let at = |i: usize| {
+                    let angle = i as f32 / CIRCLE_RESOLUTION as f32 * TAU;
+                    Vec2::from_array(*center) + Vec2::new(angle.cos(), angle.sin()) * *radius
+                };
+                (0..CIRCLE_RESOLUTION).map(|i| (at(i), at(i + 1))).collect()
+            }
+            Self::Polygon { points } => points.iter()
+                .zip(points.iter().cycle().skip(1))
+                .map(|(a, b)| (Vec2::from_array(*a), Vec2::from_array(*b)))
+                .collect(),
+        }
+    }
+}
+
+/// Static surroundings the worms move through, loaded from `Config::scene`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scene {
+    pub obstacles: Vec<Obstacle>,
+}
+
+impl Scene {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("could not parse {}: {}", path.display(), e))
+    }
+}
+
+#[derive(Resource, Default, Clone)]
+pub struct Obstacles(pub Vec<Obstacle>);
+
+pub fn draw_obstacles(obstacles: Option<Res<Obstacles>>, mut lines: ResMut<DebugLines>) {
+    let Some(obstacles) = obstacles else { return };
+    for obstacle in &obstacles.0 {
+        for (a, b) in obstacle.outline() {
+            lines.line_colored(a.extend(0.0), b.extend(0.0), 0.0, Color::DARK_GRAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Vec2, b: Vec2) {
+        assert!(a.distance(b) < 1e-5, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn circles_push_out_radially() {
+        let circle = Obstacle::Circle { center: [1.0, 0.0], radius: 1.0 };
+        assert_near(circle.push_out(Vec2::new(2.5, 0.0), 1.0).unwrap(), Vec2::new(0.5, 0.0));
+        assert_near(circle.push_out(Vec2::new(1.0, 0.0), 0.5).unwrap(), Vec2::new(1.5, 0.0));
+        assert!(circle.push_out(Vec2::new(1.0, 2.5), 0.5).is_none());
+    }
+
+    #[test]
+    fn polygons_push_out_through_the_nearest_edge() {
+        let square = Obstacle::Polygon { points: vec![[0.0, 0.0], [2.0, 0.0], [2.0, 2.0], [0.0, 2.0]] };
+        assert_near(square.push_out(Vec2::new(1.0, 0.25), 0.5).unwrap(), Vec2::new(0.0, -0.75));
+        assert_near(square.push_out(Vec2::new(2.25, 1.0), 0.5).unwrap(), Vec2::new(0.25, 0.0));
+        assert_near(square.push_out(Vec2::new(1.0, 2.0), 0.5).unwrap(), Vec2::new(0.0, 0.5));
+        assert!(square.push_out(Vec2::new(3.0, 1.0), 0.5).is_none());
+    }
+
+    #[test]
+    fn nodes_on_a_segment_leave_along_its_normal() {
+        let segment = Obstacle::Segment { a: [0.0, 0.0], b: [4.0, 0.0] };
+        assert_near(segment.push_out(Vec2::new(2.0, 0.0), 0.5).unwrap(), Vec2::new(0.0, 0.5));
+        assert_near(segment.push_out(Vec2::new(2.0, -0.25), 0.5).unwrap(), Vec2::new(0.0, -0.25));
+        assert!(segment.push_out(Vec2::new(2.0, 1.0), 0.5).is_none());
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    brain::{Inputs, Sensing},
+    config::{Config, WormSettings},
+    field::{Field, ScalarField},
+    physics::{Position, Spring},
+    step::SimulationStage,
+    worm::{Control, Head, Side, WormController},
+};
+
+/// Feeds one control spring's stretch to one neuron.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StretchReceptor {
+    /// The spring's `Control::index`, counted from 1 behind the head.
+    pub segment: i32,
+    pub side: Side,
+    pub neuron: usize,
+    /// Overrides `Proprioception::gain` for this receptor.
+    pub gain: Option<f32>,
+}
+
+/// Stretch receptors reading `gain * (length - rest) / rest` off control
+/// springs, so stretching excites and shortening inhibits.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Proprioception {
+    pub gain: f32,
+    pub receptors: Vec<StretchReceptor>,
+}
+
+impl Default for Proprioception {
+    fn default() -> Self {
+        Self { gain: 1.0, receptors: vec![] }
+    }
+}
+
+fn stretch_receptors(
+    config: Res<Config>,
+    mut worms: Query<&mut Inputs>,
+    springs: Query<(&Parent, &Spring, &Control)>,
+    positions: Query<&Position>
+) {
+    let proprioception = &config.proprioception;
+    if proprioception.receptors.is_empty() { return }
+    for (parent, spring, control) in springs.iter() {
+        let Ok(mut inputs) = worms.get_mut(parent.get()) else { continue };
+        let (Ok(a), Ok(b)) = (positions.get(spring.a), positions.get(spring.b)) else { continue };
+        let stretch = (a.now.distance(b.now) - spring.length) / spring.length;
+        let side = Side::of(control);
+        for receptor in proprioception.receptors.iter()
+            .filter(|r| r.segment == control.index && r.side == side) {
+            let gain = receptor.gain.unwrap_or(proprioception.gain);
+            inputs.add(receptor.neuron, (gain * stretch) as f64);
+        }
+    }
+}
+
+/// A chemical spread over the world, smelled at the worm's head.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Chemotaxis {
+    /// Concentration of the chemical, none when unset.
+    pub field: Option<Field>,
+    /// Neuron the head's reading is added to.
+    pub neuron: usize,
+    pub gain: f32,
+}
+
+impl Default for Chemotaxis {
+    fn default() -> Self {
+        Self { field: None, neuron: 0, gain: 1.0 }
+    }
+}
+
+/// Present when `Chemotaxis::field` is set.
+#[derive(Resource)]
+pub struct Chemical(pub ScalarField);
+
+fn chemoreceptor(
+    config: Res<Config>,
+    chemical: Option<Res<Chemical>>,
+    mut worms: Query<&mut Inputs>,
+    heads: Query<(&Parent, &Position), With<Head>>
+) {
+    let Some(chemical) = chemical else { return };
+    let chemotaxis = &config.chemotaxis;
+    for (parent, position) in heads.iter() {
+        let Ok(mut inputs) = worms.get_mut(parent.get()) else { continue };
+        let concentration = chemical.0.sample(position.now);
+        inputs.add(chemotaxis.neuron, (chemotaxis.gain * concentration) as f64);
+    }
+}
+
+/// A temperature field felt at the worm's head, relative to the temperature
+/// the worm prefers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thermotaxis {
+    /// Temperature over the world, none when unset.
+    pub field: Option<Field>,
+    /// Temperature worms prefer unless their `WormSpec` says otherwise.
+    pub preferred: f32,
+    /// How far from the preferred temperature still counts as on the
+    /// isotherm for the `thermotaxis` fitness.
+    pub tolerance: f32,
+    /// Neuron `gain * (temperature - preferred)` is added to.
+    pub neuron: usize,
+    pub gain: f32,
+}
+
+impl Default for Thermotaxis {
+    fn default() -> Self {
+        Self { field: None, preferred: 20.0, tolerance: 0.5, neuron: 1, gain: 1.0 }
+    }
+}
+
+/// Present when `Thermotaxis::field` is set.
+#[derive(Resource)]
+pub struct Temperature(pub ScalarField);
+
+/// The temperature a worm seeks out.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct PreferredTemperature(pub f32);
+
+fn thermoreceptor(
+    config: Res<Config>,
+    temperature: Option<Res<Temperature>>,
+    mut worms: Query<(&mut Inputs, &PreferredTemperature)>,
+    heads: Query<(&Parent, &Position), With<Head>>
+) {
+    let Some(temperature) = temperature else { return };
+    let thermotaxis = &config.thermotaxis;
+    for (parent, position) in heads.iter() {
+        let Ok((mut inputs, preferred)) = worms.get_mut(parent.get()) else { continue };
+        let difference = temperature.0.sample(position.now) - preferred.0;
+        inputs.add(thermotaxis.neuron, (thermotaxis.gain * difference) as f64);
+    }
+}
+
+/// Deepest overlap a node was pushed out of by collisions this step, read and
+/// cleared by the touch receptors.
+#[derive(Component, Debug, Clone, Copy, Default)]
+pub struct ContactSensor {
+    pub penetration: f32,
+}
+
+impl ContactSensor {
+    pub fn touch(&mut self, depth: f32) {
+        self.penetration = self.penetration.max(depth);
+    }
+}
+
+/// A node carrying a `ContactSensor`; segments count from 0 behind the head
+/// and their sides are the body plan's nodes named `left` and `right`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TouchSite {
+    Head,
+    Left(usize),
+    Right(usize),
+}
+
+/// Feeds one node's contact to one neuron.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TouchReceptor {
+    pub node: TouchSite,
+    pub neuron: usize,
+    /// Overrides `Touch::gain` for this receptor.
+    pub gain: Option<f32>,
+}
+
+/// Touch receptors adding `gain` to their neuron while their node overlaps
+/// something by more than `threshold`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Touch {
+    pub threshold: f32,
+    pub gain: f32,
+    pub receptors: Vec<TouchReceptor>,
+}
+
+impl Default for Touch {
+    fn default() -> Self {
+        Self { threshold: 0.01, gain: 1.0, receptors: vec![] }
+    }
+}
+
+fn touch_receptors(
+    config: Res<Config>,
+    settings: Res<WormSettings>,
+    mut worms: Query<(Entity, &WormController, &mut Inputs)>,
+    heads: Query<(Entity, &Parent), With<Head>>,
+    mut sensors: Query<&mut ContactSensor>
+) {
+    let touch = &config.touch;
+    if !touch.receptors.is_empty() {
+        let heads: HashMap<Entity, Entity> = heads.iter().map(|(head, parent)| (parent.get(), head)).collect();
+        for (entity, worm, mut inputs) in worms.iter_mut() {
+            let segment_node = |i: usize, name: &str| worm.segments.get(i)
+                .zip(settings.body.index(name))
+                .and_then(|(segment, node)| segment.nodes.get(node).copied());
+            for receptor in &touch.receptors {
+                let node = match receptor.node {
+                    TouchSite::Head => heads.get(&entity).copied(),
+                    TouchSite::Left(i) => segment_node(i, "left"),
+                    TouchSite::Right(i) => segment_node(i, "right"),
+                };
+                let Some(sensor) = node.and_then(|node| sensors.get(node).ok()) else { continue };
+                if sensor.penetration > touch.threshold {
+                    inputs.add(receptor.neuron, receptor.gain.unwrap_or(touch.gain) as f64);
+                }
+            }
+        }
+    }
+    for mut sensor in sensors.iter_mut() {
+        sensor.penetration = 0.0;
+    }
+}
+
+pub struct SensorPlugin;
+impl Plugin for SensorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(SimulationStage, stretch_receptors.label(Sensing));
+        app.add_system_to_stage(SimulationStage, chemoreceptor.label(Sensing).after(stretch_receptors));
+        app.add_system_to_stage(SimulationStage, thermoreceptor.label(Sensing).after(chemoreceptor));
+        app.add_system_to_stage(SimulationStage, touch_receptors.label(Sensing).after(thermoreceptor));
+    }
+}
@@ -0,0 +1,89 @@
+use bevy::{ecs::schedule::ShouldRun, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+/// Stage holding every system that advances the simulation. It runs once per
+/// substep, so physics, brains and logging always move in lockstep no matter
+/// how often frames are drawn. Within a substep the systems are ordered:
+/// growth, `Sensing`, `Thinking`, `Actuation`, forces, `Integration`, the
+/// clock and then fitness and recording.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub struct SimulationStage;
+
+#[derive(Resource, Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timestep {
+    /// Seconds of experiment time per tick.
+    pub tick: f32,
+    /// Integration step for the physics per tick.
+    pub physics_dt: f32,
+    /// Integration step for the CTRNNs per tick.
+    pub brain_dt: f32,
+    /// How many substeps each tick is split into.
+    pub substeps: u32,
+    /// Most ticks run in a single rendered frame before the GUI falls behind.
+    pub max_ticks_per_frame: u32,
+}
+
+impl Default for Timestep {
+    fn default() -> Self {
+        Self {
+            tick: 1.0 / 60.0,
+            physics_dt: 0.05,
+            brain_dt: 0.05,
+            substeps: 1,
+            max_ticks_per_frame: 4,
+        }
+    }
+}
+
+impl Timestep {
+    fn substeps(&self) -> f32 { self.substeps.max(1) as f32 }
+    pub fn tick(&self) -> f32 { self.tick / self.substeps() }
+    pub fn physics_dt(&self) -> f32 { self.physics_dt / self.substeps() }
+    pub fn brain_dt(&self) -> f64 { (self.brain_dt / self.substeps()) as f64 }
+}
+
+#[derive(Resource, Default)]
+struct Accumulator {
+    time: f32,
+    remaining: u32,
+}
+
+/// With a window, ticks follow the wall clock; headless, every frame is one tick.
+fn run_substeps(
+    time: Res<Time>,
+    timestep: Res<Timestep>,
+    config: Res<Config>,
+    mut acc: ResMut<Accumulator>
+) -> ShouldRun {
+    if acc.remaining == 0 {
+        let ticks = if config.gui {
+            acc.time += time.delta_seconds();
+            let ticks = (acc.time / timestep.tick).floor() as u32;
+            acc.time -= ticks as f32 * timestep.tick;
+            if ticks > timestep.max_ticks_per_frame {
+                acc.time = 0.0;
+            }
+            ticks.min(timestep.max_ticks_per_frame)
+        } else { 1 };
+        acc.remaining = ticks * timestep.substeps.max(1);
+        if acc.remaining == 0 { return ShouldRun::No }
+    }
+
+    acc.remaining -= 1;
+    if acc.remaining > 0 { ShouldRun::YesAndCheckAgain } else { ShouldRun::Yes }
+}
+
+pub struct StepPlugin;
+impl Plugin for StepPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Accumulator>();
+        app.add_stage_before(
+            CoreStage::Update,
+            SimulationStage,
+            SystemStage::parallel().with_run_criteria(run_substeps)
+        );
+    }
+}
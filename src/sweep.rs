@@ -0,0 +1,210 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    sync::{atomic::{AtomicUsize, Ordering}, mpsc},
+    thread,
+};
+
+use bevy::prelude::*;
+
+use crate::{brain::BrainSnapshot, config::Config, medium::Flow, Outcome, StartingBrain};
+
+/// One swept parameter, `name=start:stop:count` on the command line, sampled
+/// inclusively at `count` evenly spaced points.
+struct Axis {
+    name: String,
+    values: Vec<f32>,
+}
+
+impl Axis {
+    fn parse(spec: &str) -> Result<Self, String> {
+        let err = || format!("invalid grid `{}`, expected name=start:stop:count", spec);
+        let (name, range) = spec.split_once('=').ok_or_else(err)?;
+        let parts: Vec<&str> = range.split(':').collect();
+        if parts.len() != 3 { return Err(err()) }
+        let start: f32 = parts[0].parse().map_err(|_| err())?;
+        let stop: f32 = parts[1].parse().map_err(|_| err())?;
+        let count: usize = parts[2].parse().map_err(|_| err())?;
+        let values = match count {
+            0 => return Err(err()),
+            1 => vec![start],
+            _ => (0..count)
+                .map(|i| start + (stop - start) * i as f32 / (count - 1) as f32)
+                .collect(),
+        };
+        set(&mut Config::default(), name, start)?;
+        Ok(Self { name: name.to_string(), values })
+    }
+}
+
+fn set(config: &mut Config, name: &str, value: f32) -> Result<(), String> {
+    match name {
+        "frequency" => config.worm.frequency = value,
+        "phase" => config.worm.phase = value,
+        "neurons" => config.worm.neurons = value.round() as usize,
+        "segments" => config.worm.segments = value.round() as usize,
+        "adult_segments" => config.worm.adult_segments = value.round() as usize,
+        "run_length" => config.run_length = value,
+        "flow_x" => config.medium.flow = Flow::Uniform { velocity: [value, 0.0] },
+        "viscosity" => config.medium.viscosity = value,
+        "density" => config.medium.density = value,
+        "pressure" => config.worm.body.pressure = value,
+        _ => return Err(format!("cannot sweep over `{}`", name)),
+    }
+    Ok(())
+}
+
+/// Every combination of the axes, with the first axis varying slowest.
+fn points(axes: &[Axis]) -> Vec<Vec<f32>> {
+    axes.iter().fold(vec![vec![]], |points, axis| {
+        points.iter()
+            .flat_map(|point| axis.values.iter().map(move |value| {
+                let mut point = point.clone();
+                point.push(*value);
+                point
+            }))
+            .collect()
+    })
+}
+
+/// Runs a single headless simulation to completion and returns every
+/// selected fitness of the first worm.
+pub fn evaluate(config: Config, brain: Option<BrainSnapshot>) -> Vec<f32> {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins);
+    crate::add_simulation(&mut app, config);
+    if let Some(brain) = brain {
+        app.insert_resource(StartingBrain(brain));
+    }
+    loop {
+        app.update();
+        if let Some(scores) = app.world.resource_mut::<Outcome>().0.take() {
+            return scores.into_iter().next().unwrap_or_default();
+        }
+    }
+}
+
+/// Runs `evaluate` on every job across `threads` worker threads, reporting
+/// progress on stderr, and returns the results in job order.
+pub fn evaluate_all<T: Sync, R: Send + Default + Clone>(
+    jobs: &[T],
+    threads: usize,
+    evaluate: impl Fn(&T) -> R + Sync
+) -> Vec<R> {
+    let mut results = vec![R::default(); jobs.len()];
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            let sender = sender.clone();
+            let (next, evaluate) = (&next, &evaluate);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(i) else { break };
+                sender.send((i, evaluate(job))).unwrap();
+            });
+        }
+        drop(sender);
+        for (done, (i, fitness)) in receiver.iter().enumerate() {
+            results[i] = fitness;
+            eprint!("\r{}/{}", done + 1, jobs.len());
+        }
+        eprintln!();
+    });
+    results
+}
+
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// `blob sweep [--grid name=start:stop:count]... [--threads n] [--out file] [config flags]`
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut axes = vec![];
+    let mut threads = default_threads();
+    let mut out = None;
+    let mut rest = vec![];
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} expects a value", flag));
+        match flag.as_str() {
+            "--grid" => axes.push(Axis::parse(value()?)?),
+            "--threads" => threads = value()?.parse().map_err(|_| "invalid value for --threads")?,
+            "--out" => out = Some(value()?.clone()),
+            _ => rest.push(flag.clone()),
+        }
+    }
+    if axes.is_empty() {
+        return Err("sweep needs at least one --grid".to_string());
+    }
+
+    let mut base = Config::from_args(rest)?;
+    base.gui = false;
+    let seed = base.seed();
+    let fitness_names: Vec<String> = base.fitness.iter()
+        .map(|kind| kind.build(&base).name().to_string())
+        .collect();
+
+    let points = points(&axes);
+    let configs = points.iter()
+        .map(|point| {
+            let mut config = base.clone();
+            for (axis, value) in axes.iter().zip(point) {
+                set(&mut config, &axis.name, *value)?;
+            }
+            Ok(config)
+        })
+        .collect::<Result<Vec<Config>, String>>()?;
+
+    let results = evaluate_all(&configs, threads, |config| evaluate(config.clone(), None));
+
+    let mut writer: Box<dyn Write> = match &out {
+        Some(path) => Box::new(File::create(path).map_err(|e| format!("could not create {}: {}", path, e))?),
+        None => Box::new(io::stdout()),
+    };
+    let write = |writer: &mut Box<dyn Write>| -> io::Result<()> {
+        let names: Vec<&str> = axes.iter().map(|axis| axis.name.as_str()).collect();
+        let extra: Vec<&str> = fitness_names.iter().skip(1).map(String::as_str).collect();
+        let header: Vec<&str> = names.into_iter().chain(["fitness", "seed"]).chain(extra).collect();
+        writeln!(writer, "{}", header.join(","))?;
+        for (point, scores) in points.iter().zip(&results) {
+            let mut values: Vec<String> = point.iter().map(|v| v.to_string()).collect();
+            values.push(scores.first().map_or(String::new(), |x| x.to_string()));
+            values.push(seed.to_string());
+            values.extend(scores.iter().skip(1).map(|x| x.to_string()));
+            writeln!(writer, "{}", values.join(","))?;
+        }
+        writer.flush()
+    };
+    write(&mut writer).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_spans_start_to_stop() {
+        let axis = Axis::parse("frequency=0.5:1.5:3").unwrap();
+        assert_eq!(axis.name, "frequency");
+        assert_eq!(axis.values, vec![0.5, 1.0, 1.5]);
+        assert_eq!(Axis::parse("phase=2:4:1").unwrap().values, vec![2.0]);
+    }
+
+    #[test]
+    fn bad_axes_are_errors() {
+        for spec in ["frequency", "frequency=1:2", "frequency=1:2:0", "frequency=a:2:3", "colour=1:2:3"] {
+            assert!(Axis::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn points_cover_every_combination() {
+        let axes = [Axis::parse("frequency=1:2:2").unwrap(), Axis::parse("phase=0:1:3").unwrap()];
+        let points = points(&axes);
+        assert_eq!(points.len(), 6);
+        assert_eq!(points[0], vec![1.0, 0.0]);
+        assert_eq!(points[5], vec![2.0, 1.0]);
+    }
+}
@@ -1,12 +1,22 @@
 use bevy::prelude::*;
 use bevy_egui::egui::{self, plot::{Plot, Line, PlotPoints, PlotBounds}, Vec2};
 
-use crate::{brain::CTRNN, worm::Neurons};
+use crate::{brain::CTRNN, worm::{Neurons, WormId}, SelectedWorm};
 
-fn phase_portrait(mut egui_context: ResMut<bevy_egui::EguiContext>, ctrnns: Query<&CTRNN>) {
+/// The windows show the selected worm only; Tab cycles through worms.
+fn selected<'a, T>(mut worms: impl Iterator<Item = (&'a WormId, T)>, selected: &SelectedWorm) -> Option<T> {
+    worms.find(|(id, _)| id.0 == selected.0).map(|(_, item)| item)
+}
+
+fn phase_portrait(
+    mut egui_context: ResMut<bevy_egui::EguiContext>,
+    worm: Res<SelectedWorm>,
+    ctrnns: Query<(&WormId, &CTRNN)>
+) {
     let default = vec![0.0, 0.0, 0.0];
-    if let Ok(ctrnn) = ctrnns.get_single() {
-        egui::Window::new("Outputs")
+    if let Some(ctrnn) = selected(ctrnns.iter(), &worm) {
+        egui::Window::new(format!("Outputs (worm {})", worm.0))
+            .id(egui::Id::new("outputs"))
             .default_size(Vec2::new(300.0, 300.0))
             .show(egui_context.ctx_mut(), |ui| {
                 let line = Line::new(PlotPoints::from_parametric_callback(
@@ -26,11 +36,16 @@ fn phase_portrait(mut egui_context: ResMut<bevy_egui::EguiContext>, ctrnns: Quer
     }
 }
 
-fn flux_graph(mut egui_context: ResMut<bevy_egui::EguiContext>, ctrnns: Query<&CTRNN>) {
+fn flux_graph(
+    mut egui_context: ResMut<bevy_egui::EguiContext>,
+    worm: Res<SelectedWorm>,
+    ctrnns: Query<(&WormId, &CTRNN)>
+) {
     let default = (0.0, 0.0);
     let weight = (1, 1);
-    if let Ok(ctrnn) = ctrnns.get_single() {
-        egui::Window::new("Flux")
+    if let Some(ctrnn) = selected(ctrnns.iter(), &worm) {
+        egui::Window::new(format!("Flux (worm {})", worm.0))
+            .id(egui::Id::new("flux"))
             .default_size(Vec2::new(300.0, 300.0))
             .show(egui_context.ctx_mut(), |ui| {
                 let center = Line::new(PlotPoints::from_parametric_callback(
@@ -66,9 +81,14 @@ fn flux_graph(mut egui_context: ResMut<bevy_egui::EguiContext>, ctrnns: Query<&C
     }
 }
 
-fn outputs_graph(mut egui_context: ResMut<bevy_egui::EguiContext>, neurons: Query<&Neurons>) {
-    if let Ok(neurons) = neurons.get_single() {
-        egui::Window::new("Activity")
+fn outputs_graph(
+    mut egui_context: ResMut<bevy_egui::EguiContext>,
+    worm: Res<SelectedWorm>,
+    neurons: Query<(&WormId, &Neurons)>
+) {
+    if let Some(neurons) = selected(neurons.iter(), &worm) {
+        egui::Window::new(format!("Activity (worm {})", worm.0))
+            .id(egui::Id::new("activity"))
             .default_size(Vec2::new(300.0, 300.0))
             .show(egui_context.ctx_mut(), |ui| {
                 for neuron in &neurons.0 {
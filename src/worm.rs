@@ -1,25 +1,91 @@
-use std::{collections::VecDeque, f32::consts::PI};
+use std::{collections::HashMap, f32::consts::PI};
 
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{physics::*, brain::{CTRNN, UpdateFlux}, TimeTracker, WormSettings};
-
-const DRAG_NODE: f32 = 0.0;
-const DRAG_EDGE: f32 = 1.0;
-const SCALE: f32 = 0.5;
+use crate::{physics::*, blob::Blob, brain::{Inputs, Sensing, Thinking, CTRNN, UpdateFlux}, config::WormSettings, muscle::{drive, Muscle}, step::SimulationStage, TimeTracker};
 
 const SPRING_SOFT: f32 = 5.0 * 7.5;
 const SPRING_HARD: f32 = 5.0 * 7.5;
 const SPRING_SKELETON: f32 = 5.0 * 7.5;
 
+/// Constant and material shared by every spring of one class. A partially
+/// given class starts from `SPRING_SOFT` and a plain linear spring.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpringClass {
+    pub constant: f32,
+    pub damping: f32,
+    pub nonlinearity: Nonlinearity,
+    pub mode: SpringMode,
+    pub breaking_strain: Option<f32>,
+}
+
+impl SpringClass {
+    fn new(constant: f32) -> Self {
+        Self {
+            constant,
+            damping: 0.0,
+            nonlinearity: Nonlinearity::Linear,
+            mode: SpringMode::Both,
+            breaking_strain: None,
+        }
+    }
+
+    pub fn spring(&self, a: Entity, b: Entity, length: f32) -> (Spring, Material) {
+        (
+            Spring { a, b, constant: self.constant, length },
+            Material {
+                damping: self.damping,
+                nonlinearity: self.nonlinearity,
+                mode: self.mode,
+                breaking_strain: self.breaking_strain,
+            }
+        )
+    }
+}
+
+impl Default for SpringClass {
+    fn default() -> Self { Self::new(SPRING_SOFT) }
+}
+
+/// Materials of the springs by class, what each class connects being up to
+/// the `BodyPlan`; the comments describe the default worm.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpringClasses {
+    /// Along the center line, head included.
+    pub skeleton: SpringClass,
+    /// From the side nodes to the center line.
+    pub soft: SpringClass,
+    /// Across each segment, between its two side nodes.
+    pub brace: SpringClass,
+    /// Along each side, with lengths set by the brain.
+    pub control: SpringClass,
+}
+
+impl Default for SpringClasses {
+    fn default() -> Self {
+        Self {
+            skeleton: SpringClass::new(SPRING_SKELETON),
+            soft: SpringClass::new(SPRING_SOFT),
+            brace: SpringClass::new(SPRING_SOFT),
+            control: SpringClass::new(SPRING_HARD),
+        }
+    }
+}
+
+/// One segment's nodes, in `BodyPlan::nodes` order.
 #[derive(Debug)]
 pub struct Segment<T> {
-    index: usize,
-    center: T,
-    left: T,
-    right: T
+    pub index: usize,
+    pub nodes: Vec<T>,
 }
 
+/// Systems setting what the control springs should do this step.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct Actuation;
+
 #[derive(Component)]
 pub struct CyclicalMapping;
 #[derive(Component)]
@@ -43,23 +109,59 @@ pub struct WormController {
     pub segments: Vec<Segment<Entity>>
 }
 
+impl WormController {
+    pub fn new(func: fn(f32, f32, f32) -> f32, segments: Vec<Segment<Entity>>) -> Self {
+        Self { func, segments }
+    }
+}
+
 #[derive(Component)]
 pub struct Control {
     pub index: i32,
     pub side: f32
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    pub fn of(control: &Control) -> Self {
+        if control.side < 0.0 { Self::Left } else { Self::Right }
+    }
+
+    /// `Control::side` for this side.
+    pub fn sign(self) -> f32 {
+        match self {
+            Self::Left => -1.0,
+            Self::Right => 1.0,
+        }
+    }
+}
+
 #[derive(Component)]
-pub struct Index(usize);
-
-fn gen_segments(num_segments: i32) -> Vec<Segment<Vec3>> {
-    let offset = 0.5;
-    (0..num_segments).map(|i| Segment {
-        index: i as usize,
-        center: Vec3::new(-i as f32 - 1.0, 0.0, 0.0),
-        left: Vec3::new(-i as f32 - offset, -offset, 0.0),
-        right: Vec3::new(-i as f32 - offset, offset, 0.0),
-    }).collect()
+pub struct Index(pub usize);
+
+/// The node at the front of the worm, where its senses sit.
+#[derive(Component)]
+pub struct Head;
+
+/// Position of a worm in `Config::worms`, used to tell worms apart in logs.
+#[derive(Component, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WormId(pub usize);
+
+/// Mean position of each worm's nodes, keyed by the worm's entity.
+pub fn centers_of_mass<'a>(nodes: impl Iterator<Item = (&'a Parent, &'a Position)>) -> HashMap<Entity, Vec3> {
+    let mut totals: HashMap<Entity, (Vec3, f32)> = HashMap::new();
+    for (parent, pos) in nodes {
+        let total = totals.entry(parent.get()).or_default();
+        total.0 += pos.now;
+        total.1 += 1.0;
+    }
+    totals.into_iter().map(|(worm, (total, count))| (worm, total / count)).collect()
 }
 
 pub fn worm_builder(
@@ -67,92 +169,27 @@ pub fn worm_builder(
     position: Vec3,
     commands: &mut Commands,
     controller: fn(f32, f32, f32) -> f32,
-    neurons: usize
+    neurons: usize,
+    brain: CTRNN,
+    settings: &WormSettings
 ) -> Entity {
-    let ctrnn = CTRNN::trained_ctrnn();
-    let voltages = ctrnn.init_voltage();
-
+    let plan = &settings.body;
     let mut parts = vec![];
     let parent_id = commands.spawn((
-        Transform::default().with_translation(position),
+        Transform::default(),
         GlobalTransform::default(),
         VisibilityBundle::default(),
-        CTRNN {
-            ctrnn,
-            voltages,
-            output_history: VecDeque::new(),
-            flux_history: vec![],
-            activity_history: vec![],
-            fitness_history: vec![],
-            fitness_sum: vec![],
-            avg_fitness_sum: vec![]
-        },
+        brain,
+        Inputs::default(),
         UpdateFlux,
         Neurons(vec![0.0; neurons])
     )).with_children(|parent| {
-        let head = parent.spawn((
-            Position::new(Vec3::ZERO),
-            Force::default(),
-            Mass(1.0),
-            Drag(DRAG_NODE)
-        )).id();
-
-        let entities: Vec<Segment<Entity>> = gen_segments(num_segments as i32 + 1).iter()
-            .map(|seg| Segment {
-                index: seg.index,
-                center: parent.spawn((
-                    Position::new(seg.center * SCALE),
-                    Force::default(),
-                    Mass(1.0),
-                    Drag(DRAG_NODE),
-                    Index(seg.index)
-                )).id(),
-                left: parent.spawn((
-                    Position::new(seg.left * SCALE),
-                    Force::default(),
-                    Mass(1.0),
-                    Drag(DRAG_NODE)
-                )).id(),
-                right: parent.spawn((
-                    Position::new(seg.right * SCALE),
-                    Force::default(),
-                    Mass(1.0),
-                    Drag(DRAG_NODE)
-                )).id(),
-            }).collect();
-
-        parent.spawn(Spring { a: entities[0].left, b: head, constant: SPRING_SOFT, length: 1.0 * SCALE });
-        parent.spawn(Spring { a: entities[0].center, b: head, constant: SPRING_SKELETON, length: 1.0 * SCALE });
-        parent.spawn(Spring { a: entities[0].right, b: head, constant: SPRING_SOFT, length: 1.0 * SCALE });
-        parent.spawn(Spring { a: entities[0].center, b: entities[0].left, constant: SPRING_SOFT, length: 1.0 * SCALE });
-        parent.spawn(Spring { a: entities[0].center, b: entities[0].right, constant: SPRING_SOFT, length: 1.0 * SCALE });
-
-        for i in 1..num_segments + 1 {
-            let new = &entities[i as usize];
-            let old = &entities[(i - 1) as usize];
-
-            parent.spawn(Spring { a: new.center, b: old.center, constant: SPRING_SKELETON, length: 1.0 * SCALE });
-            parent.spawn(Spring { a: new.center, b: new.left, constant: SPRING_SOFT, length: 1.0 * SCALE });
-            parent.spawn(Spring { a: new.center, b: new.right, constant: SPRING_SOFT, length: 1.0 * SCALE });
-            parent.spawn(Spring { a: new.left, b: old.center, constant: SPRING_SOFT, length: 1.0 * SCALE });
-            parent.spawn(Spring { a: new.right, b: old.center, constant: SPRING_SOFT, length: 1.0 * SCALE });
-            parent.spawn((
-                Spring { a: new.left, b: new.right, constant: SPRING_SOFT, length: 2.0 * SCALE },
-                SpringHidden
-            ));
-            parent.spawn((
-                Spring { a: new.left, b: old.left, constant: SPRING_HARD, length: 1.0 * SCALE },
-                Control { index: i as i32, side: -1.0 },
-                Drag(DRAG_EDGE)
-            ));
-            parent.spawn((
-                Spring { a: new.right, b: old.right, constant: SPRING_HARD, length: 1.0 * SCALE },
-                Control { index: i as i32, side: 1.0 },
-                Drag(DRAG_EDGE)
-            ));
+        let mut prev = plan.spawn_head(parent, position);
+        for i in 0..num_segments + 1 {
+            let segment = plan.spawn_segment(parent, &settings.springs, i, &plan.layout(i, position), &prev);
+            prev = segment.nodes.clone();
+            parts.push(segment);
         }
-
-        parts = entities;
     }).id();
 
     commands.entity(parent_id).insert(
@@ -168,40 +205,41 @@ fn worm_control_system(
         Without<RegionalMapping>,
         Without<FrequencyMapping>
     )>,
-    mut nodes: Query<(&Parent, &mut Spring, &Control)>,
+    mut nodes: Query<(&Parent, &mut Spring, &Control, Option<&mut Muscle>)>,
     time: Res<TimeTracker>
 ) {
-    for (parent, mut spring, control) in nodes.iter_mut() {
+    for (parent, mut spring, control, muscle) in nodes.iter_mut() {
         if let Ok((worm, _ctrnn)) = worms.get(parent.get()) {
-            spring.length = (worm.func)(
+            let length = (worm.func)(
                 time.0,
                 control.index as f32,
                 control.side
             );
+            drive(&mut spring, muscle, length);
         }
     }
 }
 
 fn cyclical_neuron_mapping(
     worms: Query<(&WormController, &Neurons), With<CyclicalMapping>>,
-    mut springs: Query<(&Parent, &mut Spring, &Control)>,
+    mut springs: Query<(&Parent, &mut Spring, &Control, Option<&mut Muscle>)>,
 ) {
-    for (parent, mut spring, control) in springs.iter_mut() {
+    for (parent, mut spring, control, muscle) in springs.iter_mut() {
         if let Ok((_worm, neurons)) = worms.get(parent.get()) {
             let outputs = neurons.0.clone();
             let index = control.index - 1;
             let index = index % outputs.len() as i32;
             let value = outputs[index as usize] as f32 - 0.5;
-            spring.length = 0.5 + value * 0.5 * control.side;
+            drive(&mut spring, muscle, 0.5 + value * 0.5 * control.side);
         }
     }
 }
 
 fn regional_neuron_mapping(
     worms: Query<(&WormController, &Neurons), With<RegionalMapping>>,
-    mut springs: Query<(&Parent, &mut Spring, &Control)>,
+    mut springs: Query<(&Parent, &mut Spring, &Control, Option<&mut Muscle>)>,
 ) {
-    for (parent, mut spring, control) in springs.iter_mut() {
+    for (parent, mut spring, control, muscle) in springs.iter_mut() {
         if let Ok((worm, neurons)) = worms.get(parent.get()) {
             let outputs = neurons.0.clone();
             let len = (worm.segments.len() - 1) as f32;
@@ -209,101 +247,45 @@ fn regional_neuron_mapping(
             let index = (control.index - 1) as f32;
             let index = (index / len * neurons).floor();
             let value = outputs[index as usize] as f32 - 0.5;
-            spring.length = 0.5 + value * 0.5 * control.side;
+            drive(&mut spring, muscle, 0.5 + value * 0.5 * control.side);
         }
     }
 }
 
 fn frequency_neuron_mapping(
     worms: Query<(&WormController, &CTRNN, &FrequencyMapping)>,
-    mut springs: Query<(&Parent, &mut Spring, &Control)>,
+    mut springs: Query<(&Parent, &mut Spring, &Control, Option<&mut Muscle>)>,
     time: Res<TimeTracker>
 ) {
-    for (parent, mut spring, control) in springs.iter_mut() {
+    for (parent, mut spring, control, muscle) in springs.iter_mut() {
         if let Ok((_worm, _ctrnn, fm)) = worms.get(parent.get()) {
             let phase = control.index as f32 * std::f32::consts::PI / fm.phase;
             let u = (-time.0 * 60.0 / fm.frequency + phase).sin() * control.side;
-            spring.length = 0.5 + u * 0.2
+            drive(&mut spring, muscle, 0.5 + u * 0.2);
         }
     }
 }
 
 fn add_worm_segment(
-    mut worms: Query<(Entity, &mut WormController)>,
-    positions: Query<(Entity, &Position)>,
+    mut worms: Query<(Entity, &mut WormController, &mut crate::Adder), Without<Blob>>,
+    positions: Query<&Position>,
     mut commands: Commands,
-    mut adder: ResMut<crate::Adder>,
+    settings: Res<WormSettings>,
 ) {
-    if adder.segment > 0 {
-        adder.segment -= 1;
-        if let Ok((entity, mut worm)) = worms.get_single_mut() {
+    let plan = &settings.body;
+    for (entity, mut worm, mut adder) in worms.iter_mut() {
+        if adder.segment > 0 {
+            adder.segment -= 1;
             let length = worm.segments.len();
-            let last = &worm.segments[length - 1].center;
-            let prev = &worm.segments[length - 2].center;
-            let last = positions.get(*last).unwrap().1.now;
-            let prev = positions.get(*prev).unwrap().1.now;
-            let diff = prev - last;
+            if length < 2 { continue }
+            let at = |segment: &Segment<Entity>| -> Vec<Vec3> {
+                segment.nodes.iter().map(|node| positions.get(*node).unwrap().now).collect()
+            };
+            let grown = plan.grow(&at(&worm.segments[length - 2]), &at(&worm.segments[length - 1]));
+            let prev = worm.segments[length - 1].nodes.clone();
             commands.entity(entity).with_children(|parent| {
-                let center = last - diff;
-                let half = last - diff * 0.5;
-                let perp = diff.cross(Vec3::new(0.0, 0.0, 1.0)) / 1.0;
-                let seg = Segment {
-                    index: length,
-                    center,
-                    left: Vec3::new(half.x + perp.x, half.y + perp.y, 0.0),
-                    right: Vec3::new(half.x - perp.x, half.y - perp.y, 0.0),
-                };
-                let old = &worm.segments[length - 1];
-                let old = Segment {
-                    index: old.index,
-                    center: positions.get(old.center).unwrap().0,
-                    left: positions.get(old.left).unwrap().0,
-                    right: positions.get(old.right).unwrap().0
-                };
-                let new = Segment {
-                    index: seg.index,
-                    center: parent.spawn((
-                        Position::new(seg.center),
-                        Force::default(),
-                        Mass(1.0),
-                        Drag(DRAG_NODE),
-                        Index(seg.index)
-                    )).id(),
-                    left: parent.spawn((
-                        Position::new(seg.left),
-                        Force::default(),
-                        Mass(1.0),
-                        Drag(DRAG_NODE),
-                    )).id(),
-                    right: parent.spawn((
-                        Position::new(seg.right),
-                        Force::default(),
-                        Mass(1.0),
-                        Drag(DRAG_NODE),
-                    )).id()
-                };
-
-                parent.spawn(Spring { a: new.center, b: old.center, constant: SPRING_SKELETON, length: 1.0 * SCALE });
-                parent.spawn(Spring { a: new.center, b: new.left, constant: SPRING_SOFT, length: 1.0 * SCALE });
-                parent.spawn(Spring { a: new.center, b: new.right, constant: SPRING_SOFT, length: 1.0 * SCALE });
-                parent.spawn(Spring { a: new.left, b: old.center, constant: SPRING_SOFT, length: 1.0 * SCALE });
-                parent.spawn(Spring { a: new.right, b: old.center, constant: SPRING_SOFT, length: 1.0 * SCALE });
-                parent.spawn((
-                    Spring { a: new.left, b: new.right, constant: SPRING_SOFT, length: 2.0 * SCALE },
-                    SpringHidden
-                ));
-                parent.spawn((
-                    Spring { a: new.left, b: old.left, constant: SPRING_HARD, length: 1.0 * SCALE },
-                    Control { index: seg.index as i32, side: -1.0 },
-                    Drag(DRAG_EDGE)
-                ));
-                parent.spawn((
-                    Spring { a: new.right, b: old.right, constant: SPRING_HARD, length: 1.0 * SCALE },
-                    Control { index: seg.index as i32, side: 1.0 },
-                    Drag(DRAG_EDGE)
-                ));
-
-                worm.segments.push(new);
+                let segment = plan.spawn_segment(parent, &settings.springs, length, &grown, &prev);
+                worm.segments.push(segment);
             });
         }
     }
@@ -335,12 +317,24 @@ fn adjust_neurons(
 pub struct WormPlugin;
 impl Plugin for WormPlugin {
     fn build(&self, app: &mut App) {
-        app.add_system(worm_control_system);
-        app.add_system(cyclical_neuron_mapping);
-        app.add_system(regional_neuron_mapping);
-        app.add_system(frequency_neuron_mapping);
-        app.add_system(add_worm_segment);
-        app.add_system(manually_adjust_neurons);
-        app.add_system(adjust_neurons);
+        app.add_system_to_stage(SimulationStage, add_worm_segment.after(crate::devo_timer).before(Sensing));
+        app.add_system_to_stage(SimulationStage, manually_adjust_neurons.after(Thinking));
+        app.add_system_to_stage(SimulationStage, adjust_neurons.after(Thinking));
+        app.add_system_to_stage(
+            SimulationStage,
+            worm_control_system.label(Actuation).after(manually_adjust_neurons).after(adjust_neurons)
+        );
+        app.add_system_to_stage(
+            SimulationStage,
+            cyclical_neuron_mapping.label(Actuation).after(manually_adjust_neurons).after(adjust_neurons)
+        );
+        app.add_system_to_stage(
+            SimulationStage,
+            regional_neuron_mapping.label(Actuation).after(manually_adjust_neurons).after(adjust_neurons)
+        );
+        app.add_system_to_stage(
+            SimulationStage,
+            frequency_neuron_mapping.label(Actuation).after(manually_adjust_neurons).after(adjust_neurons)
+        );
     }
 }